@@ -9,10 +9,15 @@ if [[ -z "$FILE_PATH" ]]; then echo "Need file argument"; exit 1; fi
 # Thanks to CDI, can be hardcoded. 
 IN_CONTAINER_DEVICE="cuda:0"
 
-# The GPU that SRVRS will make accessible to the container (different from above)
-NVIDIA_GPU="$2" 
+# The GPU(s) that SRVRS will make accessible to the container (different from above),
+# as CDI device names. SRVRS hands them out by UUID, so this is the card it
+# actually reserved.
+GPU_DEVICES=""
+for CDI_DEVICE in ${SRVRS_GPU_CDI_DEVICES//,/ }; do
+	GPU_DEVICES="$GPU_DEVICES --device $CDI_DEVICE"
+done
 # Fallback to GPU 0 if GPU is not provided
-if [[ -z "$NVIDIA_GPU" ]]; then NVIDIA_GPU="0"; fi 
+if [[ -z "$GPU_DEVICES" ]]; then GPU_DEVICES="--device nvidia.com/gpu=0"; fi
 
 SD_OUTPUT="/workdir/output.png"
 
@@ -22,7 +27,7 @@ echo "Output: $SD_OUTPUT"
 echo "chom"
 
 podman run --rm -it				  \
-	--device nvidia.com/gpu=$NVIDIA_GPU	  \
+	$GPU_DEVICES	  \
 	-v $(dirname $FILE_PATH):/workdir:Z	  \
 	-e SD2_PROMPT=$(basename $FILE_PATH)	  \
 	-e SD2_DEVICE=$IN_CONTAINER_DEVICE		  \
@@ -19,6 +19,9 @@ else
     echo "$USER not found"
     sudo useradd $USER
 fi
+# Jobs run in transient units in the srvrs user's own systemd instance, which
+# only exists while they're logged in unless they linger.
+sudo loginctl enable-linger $USER
 
 sudo rm -rf $BASE
 sudo mkdir -p $BASE $SCRIPTS
@@ -29,31 +32,18 @@ sudo chown -R $USER:$GROUP $BASE
 
 # Install systemd service and binary
 sudo cp res/srvrs.yaml /etc/
-sudo cp res/srvrs.service /etc/systemd/system/
+sed "s/@SRVRS_UID@/$(id -u $USER)/" res/srvrs.service | sudo tee /etc/systemd/system/srvrs.service > /dev/null
 sudo cp res/srvrs-distributor.service /etc/systemd/system/
 sudo install target/release/$APP /usr/local/sbin/$APP 
 sudo install target/release/$APP-distributor /usr/local/sbin/$APP-distributor
 
 sudo /usr/local/sbin/srvrs setup -c /etc/srvrs.yaml 
 
-# Switch user to srvrs and build the containers
-build_containers() {
-	set -e # Shit and die if something breaks
-	echo "Building containers..."
-	cd $BASE/ai/
-	for dir in ./*; do
-		cd $dir
-		echo "Found AI: $(pwd)"
-		podman build . --tag srvrs-$(basename $dir)
-		cd ..
-	done
-	exit
-	echo "Finished building containers"
-}
-
+# Build the containers as srvrs, since rootless podman keeps images per user.
+# srvrs build only rebuilds the ones that changed.
 sudo cp -r ai $BASE/
-sudo su -c "export BASE=$BASE; $(declare -f build_containers); build_containers" \
-	-s /bin/bash $USER
+sudo chown -R $USER:$GROUP $BASE/ai
+sudo su -c "/usr/local/sbin/srvrs build -c /etc/srvrs.yaml" -s /bin/bash $USER
 
 # Launch srvrs!
 sudo systemctl enable srvrs
@@ -0,0 +1,170 @@
+// Logging for both srvrs and the distributor. Each reads a `logging` section
+// from the config file, which flags can override.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    sync::Mutex,
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Stderr,
+    Journald,
+    File,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LoggingConfig {
+    pub level: Option<LevelFilter>, // error, warn, info, debug or trace
+    pub backend: Option<Backend>, // Journald when run by systemd, stderr otherwise, if not set
+    pub file: Option<String>, // Where the file backend writes
+}
+
+impl LoggingConfig {
+    // Flags win over the config file
+    pub fn with_overrides(mut self, level: Option<LevelFilter>, backend: Option<Backend>) -> LoggingConfig {
+        self.level = level.or(self.level);
+        self.backend = backend.or(self.backend);
+        self
+    }
+}
+
+thread_local! {
+    // What the current thread is working for, like an activity, for lines
+    // logged by code that doesn't know
+    static SCOPE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_scope(name: &str) {
+    SCOPE.with(|scope| *scope.borrow_mut() = Some(name.to_string()));
+}
+
+enum Output {
+    Stderr,
+    Journald,
+    File(Mutex<fs::File>),
+}
+
+struct Logger {
+    level: LevelFilter,
+    output: Output,
+    scoped: HashMap<String, Mutex<fs::File>>, // Extra files for each scope that has one
+}
+
+fn open_log(path: &str) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).mode(0o640).open(path)
+}
+
+fn line(record: &Record) -> String {
+    format!(
+        "{} {:<5} [{}] {}\n",
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = line(record);
+        match &self.output {
+            Output::Stderr => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            // Don't lose the line if journald's gone away
+            Output::Journald => {
+                if systemd_journal_logger::journal_send(record, std::iter::empty::<&(&str, &str)>()).is_err() {
+                    let _ = io::stderr().write_all(line.as_bytes());
+                }
+            }
+            Output::File(file) => {
+                if let Ok(mut file) = file.lock() {
+                    let _ = file.write_all(line.as_bytes());
+                }
+            }
+        }
+        // Lines logged for a scope name it as their target, from whichever
+        // thread. Anything else goes by the thread's scope.
+        let file = match self.scoped.get(record.target()) {
+            Some(file) => Some(file),
+            None => SCOPE.with(|scope| scope.borrow().as_ref().and_then(|s| self.scoped.get(s))),
+        };
+        if let Some(Ok(mut file)) = file.map(|f| f.lock()) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+// Set up logging. `default_level` is used if neither the config nor a flag
+// set one. `scoped` maps scopes, like activity names, to extra log files.
+pub fn init(config: &LoggingConfig, default_level: LevelFilter, scoped: &HashMap<String, String>) -> anyhow::Result<()> {
+    let backend = config.backend.unwrap_or(match systemd_journal_logger::connected_to_journal() {
+        true => Backend::Journald,
+        false => Backend::Stderr,
+    });
+    let output = match backend {
+        Backend::Stderr => Output::Stderr,
+        Backend::Journald => Output::Journald,
+        Backend::File => match &config.file {
+            Some(path) => Output::File(Mutex::new(
+                open_log(path).map_err(|e| anyhow::anyhow!("Could not open {}: {}", path, e))?,
+            )),
+            None => return Err(anyhow::anyhow!("Logging to a file needs a file to log to")),
+        },
+    };
+    let mut files = HashMap::new();
+    for (scope, path) in scoped {
+        let file = open_log(path).map_err(|e| anyhow::anyhow!("Could not open {}: {}", path, e))?;
+        files.insert(scope.clone(), Mutex::new(file));
+    }
+    let level = config.level.unwrap_or(default_level);
+    log::set_boxed_logger(Box::new(Logger { level, output, scoped: files }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn scoped_lines_reach_their_file_from_any_thread() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("whisper.log");
+        let logger = Logger {
+            level: LevelFilter::Info,
+            output: Output::File(Mutex::new(open_log(tmp.path().join("all.log").to_str().unwrap()).unwrap())),
+            scoped: HashMap::from([("whisper".to_string(), Mutex::new(open_log(path.to_str().unwrap()).unwrap()))]),
+        };
+        let log = |target: &str, message: &str| {
+            logger.log(&Record::builder().level(log::Level::Info).target(target).args(format_args!("{}", message)).build());
+        };
+        thread::scope(|s| {
+            s.spawn(|| log("whisper", "cancelled from the API"));
+        });
+        log("srvrs::api", "somebody else's business");
+        let scoped = fs::read_to_string(&path).unwrap();
+        assert!(scoped.contains("cancelled from the API"), "{}", scoped);
+        assert!(!scoped.contains("somebody else's business"), "{}", scoped);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{OpenOptionsExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Component, Path},
+};
+
+// Everything here works relative to directories we already have open and
+// never follows a symlink, so nobody can swap a path out from under us
+// between looking at it and acting on it.
+
+// What's at a name, without following it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Device,
+    Other, // FIFOs and sockets
+}
+
+// Open a directory, refusing to go through a symlink to get there.
+pub fn open_dir(path: &Path) -> io::Result<File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+// Open a directory somewhere under one we trust, without following a
+// symlink anywhere on the way down.
+pub fn open_under(root: &Path, path: &Path) -> io::Result<File> {
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not under the root"))?;
+    let mut dir = open_dir(root)?;
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => dir = open_dir_at(&dir, name)?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a plain path")),
+        }
+    }
+    Ok(dir)
+}
+
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Name has a NUL in it"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn open_at(dir: &File, name: &OsStr, flags: libc::c_int) -> io::Result<File> {
+    let name = c_name(name)?;
+    // SAFETY: name is a valid C string for the whole call.
+    let fd = check(unsafe {
+        // Anything we create starts out ours alone
+        libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, 0o600 as libc::c_uint)
+    })?;
+    // SAFETY: openat just gave us this fd and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn open_dir_at(dir: &File, name: &OsStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_RDONLY | libc::O_DIRECTORY)
+}
+
+// Non-blocking, so that somebody swapping in a FIFO can't hang us
+pub fn open_file_at(dir: &File, name: &OsStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_RDONLY | libc::O_NONBLOCK)
+}
+
+pub fn open_file_rw_at(dir: &File, name: &OsStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_RDWR | libc::O_NONBLOCK)
+}
+
+// An unnamed file on the same filesystem as dir, which goes away once it's
+// closed, and which nobody else can open in the meantime
+pub fn temp_file_in(dir: &File) -> io::Result<File> {
+    open_at(dir, OsStr::new("."), libc::O_RDWR | libc::O_TMPFILE)
+}
+
+// Create a new file, failing if there's anything there already
+pub fn create_file_at(dir: &File, name: &OsStr) -> io::Result<File> {
+    open_at(dir, name, libc::O_RDWR | libc::O_CREAT | libc::O_EXCL)
+}
+
+pub fn kind_at(dir: &File, name: &OsStr) -> io::Result<Kind> {
+    let name = c_name(name)?;
+    // SAFETY: stat is plain old data, all zeroes is a valid value.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // SAFETY: name is a valid C string and stat is valid for writes.
+    check(unsafe {
+        libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW)
+    })?;
+    Ok(match stat.st_mode & libc::S_IFMT {
+        libc::S_IFREG => Kind::File,
+        libc::S_IFDIR => Kind::Dir,
+        libc::S_IFLNK => Kind::Symlink,
+        libc::S_IFCHR | libc::S_IFBLK => Kind::Device,
+        _ => Kind::Other,
+    })
+}
+
+pub fn exists_at(dir: &File, name: &OsStr) -> bool {
+    kind_at(dir, name).is_ok()
+}
+
+// The names in an open directory
+pub fn entries(dir: &File) -> io::Result<Vec<OsString>> {
+    // The fd's entry in /proc is the directory we opened, wherever it's been
+    // moved to since.
+    fs::read_dir(format!("/proc/self/fd/{}", dir.as_raw_fd()))?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect()
+}
+
+// Make a directory, returning whether it's new. Anything already there is
+// left for the caller to open and check.
+pub fn mkdir_at(dir: &File, name: &OsStr, mode: u32) -> io::Result<bool> {
+    let name = c_name(name)?;
+    // SAFETY: name is a valid C string for the whole call.
+    match check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode as libc::mode_t) }) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn rename_at(from_dir: &File, from: &OsStr, to_dir: &File, to: &OsStr) -> io::Result<()> {
+    let from = c_name(from)?;
+    let to = c_name(to)?;
+    // SAFETY: both names are valid C strings for the whole call.
+    check(unsafe {
+        libc::renameat(from_dir.as_raw_fd(), from.as_ptr(), to_dir.as_raw_fd(), to.as_ptr())
+    })?;
+    Ok(())
+}
+
+fn unlink_at(dir: &File, name: &OsStr, flags: libc::c_int) -> io::Result<()> {
+    let name = c_name(name)?;
+    // SAFETY: name is a valid C string for the whole call.
+    check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+    Ok(())
+}
+
+// Remove a file, or whatever else that isn't a directory is there, without
+// following it. Returns whether there was anything to remove.
+pub fn remove_file_at(dir: &File, name: &OsStr) -> io::Result<bool> {
+    match unlink_at(dir, name, 0) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Replace whatever's at name with a new file holding contents, in one go.
+// It's written to a new file of its own first, so nothing that was put there
+// ahead of us gets written through.
+pub fn replace_file_at(dir: &File, name: &OsStr, contents: &[u8], mode: u32) -> io::Result<()> {
+    let mut tmp = OsString::from(".");
+    tmp.push(name);
+    tmp.push(".tmp");
+    remove_file_at(dir, &tmp)?;
+    let mut file = create_file_at(dir, &tmp)?;
+    let written = file.write_all(contents)
+        .and_then(|_| file.set_permissions(fs::Permissions::from_mode(mode)))
+        .and_then(|_| rename_at(dir, &tmp, dir, name));
+    if written.is_err() {
+        let _ = remove_file_at(dir, &tmp);
+    }
+    written
+}
+
+// Delete something and, if it's a directory, everything in it
+pub fn remove_tree_at(dir: &File, name: &OsStr) -> io::Result<()> {
+    if kind_at(dir, name)? != Kind::Dir {
+        return unlink_at(dir, name, 0);
+    }
+    let subdir = open_dir_at(dir, name)?;
+    for entry in entries(&subdir)? {
+        remove_tree_at(&subdir, &entry)?;
+    }
+    unlink_at(dir, name, libc::AT_REMOVEDIR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    #[test]
+    fn replace_file_at_does_not_write_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("target");
+        fs::write(&target, "untouched").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        let status = tmp.path().join("status");
+        fs::create_dir(&status).unwrap();
+        symlink(&target, status.join("notice")).unwrap();
+        // A leftover temp file pointing somewhere else doesn't get followed either
+        symlink(&target, status.join(".notice.tmp")).unwrap();
+
+        let dir = open_dir(&status).unwrap();
+        replace_file_at(&dir, OsStr::new("notice"), b"held\n", 0o644).unwrap();
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+        assert_eq!(fs::metadata(&target).unwrap().mode() & 0o777, 0o600);
+        let notice = fs::symlink_metadata(status.join("notice")).unwrap();
+        assert!(notice.is_file());
+        assert_eq!(notice.mode() & 0o777, 0o644);
+        assert_eq!(fs::read_to_string(status.join("notice")).unwrap(), "held\n");
+        assert!(!exists_at(&dir, OsStr::new(".notice.tmp")));
+
+        assert!(remove_file_at(&dir, OsStr::new("notice")).unwrap());
+        assert!(!remove_file_at(&dir, OsStr::new("notice")).unwrap());
+    }
+
+    #[test]
+    fn open_dir_refuses_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        symlink(tmp.path(), tmp.path().join("link")).unwrap();
+        assert!(open_dir(&tmp.path().join("link")).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Just enough of Prometheus's text format for srvrs and the distributor to
+// report on themselves without pulling in a client library.
+
+use std::fmt::Write;
+
+// Buckets, in seconds, for things that take anywhere from a moment to hours,
+// like jobs and the waits around them
+pub const SECONDS_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 14400.0,
+];
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64], // Upper bounds of each bucket, smallest first
+    counts: Vec<u64>, // How many observations fell in each bucket, not counting the ones below it
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    // Write out the buckets, sum and count. `labels` go on every line.
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            sample(out, &format!("{}_bucket", name), &join(labels, &label("le", &bound.to_string())), cumulative as f64);
+        }
+        sample(out, &format!("{}_bucket", name), &join(labels, &label("le", "+Inf")), self.count as f64);
+        sample(out, &format!("{}_sum", name), labels, self.sum);
+        sample(out, &format!("{}_count", name), labels, self.count as f64);
+    }
+}
+
+// The HELP and TYPE lines that go before a metric's samples
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn sample(out: &mut String, name: &str, labels: &str, value: f64) {
+    let _ = match labels.is_empty() {
+        true => writeln!(out, "{} {}", name, value),
+        false => writeln!(out, "{}{{{}}} {}", name, labels, value),
+    };
+}
+
+// A label, with its value escaped
+pub fn label(name: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{}=\"{}\"", name, value)
+}
+
+pub fn join(labels: &str, more: &str) -> String {
+    match labels.is_empty() {
+        true => more.to_string(),
+        false => format!("{},{}", labels, more),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let mut histogram = Histogram::new(&[1.0, 5.0, 15.0]);
+        for value in [0.5, 1.0, 3.0, 20.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "wait_seconds", &label("activity", "whisper"));
+        assert_eq!(out, "\
+wait_seconds_bucket{activity=\"whisper\",le=\"1\"} 2
+wait_seconds_bucket{activity=\"whisper\",le=\"5\"} 3
+wait_seconds_bucket{activity=\"whisper\",le=\"15\"} 3
+wait_seconds_bucket{activity=\"whisper\",le=\"+Inf\"} 4
+wait_seconds_sum{activity=\"whisper\"} 24.5
+wait_seconds_count{activity=\"whisper\"} 4
+");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("file", "a \"b\"\\c\nd"), r#"file="a \"b\"\\c\nd""#);
+        let mut out = String::new();
+        sample(&mut out, "up", &join("", &label("job", "x")), 1.0);
+        sample(&mut out, "up", "", 0.0);
+        assert_eq!(out, "up{job=\"x\"} 1\nup 0\n");
+    }
+}
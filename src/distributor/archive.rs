@@ -0,0 +1,277 @@
+use chrono::{Datelike, TimeZone, Timelike};
+use serde::Deserialize;
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, Metadata},
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+use crate::copy::CopyOwner;
+use crate::nofollow::{self, Kind};
+
+// What shape results are delivered in. Has to agree with srvrs.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "directory")]
+    Directory,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl Format {
+    // What goes on the end of the delivery's name
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Format::Directory => None,
+            Format::TarZst => Some(".tar.zst"),
+            Format::Zip => Some(".zip"),
+        }
+    }
+}
+
+// Pack a job directory into `out`, with everything under a directory called
+// `top`, so it unpacks into one place. `exclude` is left out of the top
+// level. Entries get the owner and modes they'd have been delivered with,
+// and keep their timestamps.
+pub fn write(
+    src: &File,
+    format: Format,
+    top: &Path,
+    exclude: Option<&OsStr>,
+    owner: &CopyOwner,
+    out: &File,
+) -> io::Result<()> {
+    match format {
+        Format::Directory => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not an archive")),
+        Format::TarZst => {
+            let mut tar = tar::Builder::new(zstd::Encoder::new(out, 0)?);
+            add_to_tar(&mut tar, src, top, exclude, owner)?;
+            tar.into_inner()?.finish()?;
+        }
+        Format::Zip => {
+            let mut zip = ZipWriter::new(out);
+            add_to_zip(&mut zip, src, top, exclude, owner)?;
+            zip.finish()?;
+        }
+    }
+    out.sync_all()
+}
+
+// Whatever's in a directory we're willing to pack, as it was checked
+// before delivery
+fn contents(dir: &File, exclude: Option<&OsStr>) -> io::Result<Vec<(OsString, Kind)>> {
+    let mut found = vec![];
+    for name in nofollow::entries(dir)? {
+        if Some(name.as_os_str()) == exclude {
+            continue;
+        }
+        match nofollow::kind_at(dir, &name)? {
+            kind @ (Kind::Dir | Kind::File) => found.push((name, kind)),
+            kind => return Err(io::Error::other(format!("Won't pack a {:?}", kind))),
+        }
+    }
+    Ok(found)
+}
+
+fn open_checked(dir: &File, name: &OsStr) -> io::Result<(File, Metadata)> {
+    let file = nofollow::open_file_at(dir, name)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() || meta.nlink() > 1 {
+        return Err(io::Error::other("Changed since it was checked"));
+    }
+    Ok((file, meta))
+}
+
+fn add_to_tar<W: io::Write>(
+    tar: &mut tar::Builder<W>,
+    dir: &File,
+    path: &Path,
+    exclude: Option<&OsStr>,
+    owner: &CopyOwner,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&dir.metadata()?, tar::HeaderMode::Complete);
+    header.set_mode(owner.modes.dir_mode);
+    header.set_uid(owner.uid as u64);
+    header.set_gid(owner.gid as u64);
+    tar.append_data(&mut header, path, io::empty())?;
+    for (name, kind) in contents(dir, exclude)? {
+        let entry = path.join(&name);
+        match kind {
+            Kind::Dir => add_to_tar(tar, &nofollow::open_dir_at(dir, &name)?, &entry, None, owner)?,
+            _ => {
+                let (file, meta) = open_checked(dir, &name)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata_in_mode(&meta, tar::HeaderMode::Complete);
+                header.set_mode(owner.modes.file_mode_for(meta.mode()));
+                header.set_uid(owner.uid as u64);
+                header.set_gid(owner.gid as u64);
+                // Never write more than the header says is there
+                tar.append_data(&mut header, &entry, file.take(meta.len()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn add_to_zip<W: io::Write + io::Seek>(
+    zip: &mut ZipWriter<W>,
+    dir: &File,
+    path: &Path,
+    exclude: Option<&OsStr>,
+    owner: &CopyOwner,
+) -> io::Result<()> {
+    let options = |meta: &Metadata, mode: u32| {
+        FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(mode)
+            .last_modified_time(zip_time(meta.mtime()))
+            .large_file(meta.len() >= u32::MAX as u64)
+    };
+    zip.add_directory(zip_name(path)?, options(&dir.metadata()?, owner.modes.dir_mode))?;
+    for (name, kind) in contents(dir, exclude)? {
+        let entry = path.join(&name);
+        match kind {
+            Kind::Dir => add_to_zip(zip, &nofollow::open_dir_at(dir, &name)?, &entry, None, owner)?,
+            _ => {
+                let (file, meta) = open_checked(dir, &name)?;
+                zip.start_file(zip_name(&entry)?, options(&meta, owner.modes.file_mode_for(meta.mode())))?;
+                io::copy(&mut file.take(meta.len()), zip)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Zip wants names as UTF-8
+fn zip_name(path: &Path) -> io::Result<String> {
+    match path.to_str() {
+        Some(name) => Ok(name.to_string()),
+        None => Err(io::Error::other(format!("Won't zip {}, its name isn't UTF-8", path.display()))),
+    }
+}
+
+// Zip keeps local time, and nothing from before 1980
+fn zip_time(mtime: i64) -> zip::DateTime {
+    chrono::offset::Local
+        .timestamp_opt(mtime, 0)
+        .single()
+        .and_then(|t| {
+            zip::DateTime::from_date_and_time(
+                t.year().try_into().ok()?,
+                t.month() as u8,
+                t.day() as u8,
+                t.hour() as u8,
+                t.minute() as u8,
+                t.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ownership::ModePolicy;
+    use std::{
+        collections::BTreeMap,
+        fs,
+        os::unix::fs::{symlink, PermissionsExt},
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    const MODES: ModePolicy = ModePolicy { dir_mode: 0o755, file_mode: 0o644 };
+    const OWNER: CopyOwner = CopyOwner { uid: 1234, gid: 5678, modes: &MODES };
+    const THEN: u64 = 1_681_300_000; // Even, since zip only keeps every other second
+
+    // A job's results, with the upload it started from, all last touched THEN
+    fn job(tmp: &Path) -> PathBuf {
+        let src = tmp.join("job");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("input.mp4"), "video").unwrap();
+        fs::write(src.join("lecture.srt"), "words").unwrap();
+        fs::write(src.join("sub/run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(src.join("lecture.srt"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(src.join("sub/run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(THEN);
+        for path in ["input.mp4", "lecture.srt", "sub/run.sh", "sub", ""] {
+            File::open(src.join(path)).unwrap().set_modified(then).unwrap();
+        }
+        src
+    }
+
+    fn pack(src: &Path, format: Format, out: &Path) -> io::Result<()> {
+        write(&nofollow::open_dir(src).unwrap(), format, Path::new("job-1"), Some(OsStr::new("input.mp4")), &OWNER, &File::create(out).unwrap())
+    }
+
+    #[test]
+    fn tar_zst_keeps_everything_but_the_input_under_one_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("job-1.tar.zst");
+        pack(&job(tmp.path()), Format::TarZst, &out).unwrap();
+
+        let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(&out).unwrap()).unwrap());
+        let mut found = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header = entry.header();
+            let seen = (header.mode().unwrap(), header.uid().unwrap(), header.gid().unwrap(), header.mtime().unwrap());
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            found.insert(entry.path().unwrap().to_path_buf(), (seen, contents));
+        }
+        let expected = |mode, contents: &str| ((mode, 1234, 5678, THEN), contents.to_string());
+        assert_eq!(found, BTreeMap::from([
+            (PathBuf::from("job-1"), expected(0o755, "")),
+            (PathBuf::from("job-1/lecture.srt"), expected(0o644, "words")),
+            (PathBuf::from("job-1/sub"), expected(0o755, "")),
+            (PathBuf::from("job-1/sub/run.sh"), expected(0o755, "#!/bin/sh")),
+        ]));
+    }
+
+    #[test]
+    fn zip_keeps_everything_but_the_input_under_one_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("job-1.zip");
+        pack(&job(tmp.path()), Format::Zip, &out).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out).unwrap()).unwrap();
+        let mut found = BTreeMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).unwrap();
+            let t = entry.last_modified();
+            let seen = (entry.unix_mode().unwrap() & 0o777, (t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second()));
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            found.insert(entry.name().to_string(), (seen, contents));
+        }
+        let t = zip_time(THEN as i64);
+        let then = (t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second());
+        assert_eq!(t.year(), 2023);
+        let expected = |mode, contents: &str| ((mode, then), contents.to_string());
+        assert_eq!(found, BTreeMap::from([
+            ("job-1/".to_string(), expected(0o755, "")),
+            ("job-1/lecture.srt".to_string(), expected(0o644, "words")),
+            ("job-1/sub/".to_string(), expected(0o755, "")),
+            ("job-1/sub/run.sh".to_string(), expected(0o755, "#!/bin/sh")),
+        ]));
+    }
+
+    #[test]
+    fn symlinks_are_not_packed() {
+        for format in [Format::TarZst, Format::Zip] {
+            let tmp = tempfile::tempdir().unwrap();
+            let src = job(tmp.path());
+            symlink("/etc/shadow", src.join("sub/shadow")).unwrap();
+            let e = pack(&src, format, &tmp.path().join("out")).err().unwrap();
+            assert!(e.to_string().contains("Won't pack"), "{:?}: {}", format, e);
+        }
+    }
+}
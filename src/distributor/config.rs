@@ -0,0 +1,116 @@
+use serde::{de, Deserialize};
+use crate::logging::LoggingConfig;
+
+// The distributor reads the same config file as srvrs, so that paths only
+// have to be set in one place. It takes base_dir from the top, like srvrs,
+// and everything of its own from the distributor section.
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub base_dir: String,
+    pub distributor: DistributorConfig,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DistributorConfig {
+    pub destination_base_dir: String, // Where results go by default, in a directory per user
+    #[serde(default = "default_dir_mode", deserialize_with = "mode_deserializer")]
+    pub dir_mode: u32, // Mode, in octal, for every directory delivered
+    #[serde(default = "default_file_mode", deserialize_with = "mode_deserializer")]
+    pub file_mode: u32, // Mode, in octal, for every file delivered. Executables stay executable.
+    #[serde(default = "default_group")]
+    pub group: String, // Group that delivered files belong to
+    #[serde(default = "default_dead_letter_dir")]
+    pub dead_letter_dir: String, // Where undeliverable results go. Has to be root's.
+    #[serde(default = "default_srvrs_user")]
+    pub srvrs_user: String, // User that srvrs runs as. Job directories have to belong to it.
+    #[serde(default = "default_reserve_mib")]
+    pub reserve_mib: u64, // Free space to leave on the destination's filesystem
+    #[serde(default = "default_retry_secs")]
+    pub retry_secs: u64, // How often to retry held deliveries
+    #[serde(default = "default_hold_hours")]
+    pub hold_hours: u64, // How long to hold a delivery before dead lettering it
+    #[serde(default = "default_ledger_path")]
+    pub ledger_path: String, // Ledger of deliveries to clean up. Only root may write there.
+    pub scratch_retention_days: Option<u64>, // How long results in destination_base_dir are kept, if not forever
+    pub home_retention_days: Option<u64>, // How long results in homes are kept, if not forever
+    #[serde(default = "default_retention_warning_days")]
+    pub retention_warning_days: u64, // How long before removing results to warn their owner
+    pub s3: Option<S3Config>, // A bucket to deliver to, for activities with sink: s3
+    #[serde(default)]
+    pub logging: LoggingConfig, // Log level, and where logs go
+}
+
+#[derive(Deserialize, Debug)]
+pub struct S3Config {
+    pub endpoint: String, // Like http://localhost:9000
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    #[serde(default)]
+    pub prefix: String, // Put in front of each user's results
+}
+
+fn default_dir_mode() -> u32 {
+    0o755
+}
+
+fn default_file_mode() -> u32 {
+    0o644
+}
+
+fn default_group() -> String {
+    "member".to_string()
+}
+
+fn default_srvrs_user() -> String {
+    "srvrs".to_string()
+}
+
+fn default_reserve_mib() -> u64 {
+    1024
+}
+
+fn default_retry_secs() -> u64 {
+    300
+}
+
+fn default_hold_hours() -> u64 {
+    168
+}
+
+fn default_dead_letter_dir() -> String {
+    "/var/lib/srvrs-distributor/dead-letter".to_string()
+}
+
+fn default_ledger_path() -> String {
+    "/var/lib/srvrs-distributor/ledger.yaml".to_string()
+}
+
+fn default_retention_warning_days() -> u64 {
+    3
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+// Modes are octal whether they're quoted or not, so 755 means 0o755
+fn mode_deserializer<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Text(String),
+        Number(u64),
+    }
+    let mode = match Mode::deserialize(deserializer)? {
+        Mode::Text(mode) => mode,
+        Mode::Number(mode) => mode.to_string(),
+    };
+    match u32::from_str_radix(&mode, 8) {
+        Ok(parsed) if parsed <= 0o7777 => Ok(parsed),
+        _ => Err(de::Error::custom(format!("{} is not an octal mode", mode))),
+    }
+}
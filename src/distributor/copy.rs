@@ -0,0 +1,184 @@
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    fs::{File, FileTimes, Metadata},
+    io::{self, Read, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+};
+use crate::nofollow::{self, Kind};
+use crate::ownership::{set_owner, ModePolicy};
+
+// Who a copy is for
+pub struct CopyOwner<'a> {
+    pub uid: u32,
+    pub gid: u32,
+    pub modes: &'a ModePolicy,
+}
+
+// Copy a tree into a new directory, name, in dir, for when it can't simply be
+// renamed there because it's on another filesystem. Everything is owned by
+// the user and given its mode as it's copied, keeps its timestamps, and is
+// read back and checked against what we read from the source. If anything
+// goes wrong, whatever was copied is removed again and the source is left
+// as it was.
+pub fn copy_tree(src: &File, dir: &File, name: &OsStr, owner: &CopyOwner) -> io::Result<()> {
+    if !nofollow::mkdir_at(dir, name, 0o700)? {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Destination is taken"));
+    }
+    let result = nofollow::open_dir_at(dir, name).and_then(|dst| copy_dir(src, &dst, owner));
+    if result.is_err() {
+        // Don't leave half a copy behind
+        let _ = nofollow::remove_tree_at(dir, name);
+    }
+    result
+}
+
+// The directory itself is handed over last, so that nobody else can get at
+// what's in it until it's all there.
+fn copy_dir(src: &File, dst: &File, owner: &CopyOwner) -> io::Result<()> {
+    for name in nofollow::entries(src)? {
+        match nofollow::kind_at(src, &name)? {
+            Kind::Dir => {
+                if !nofollow::mkdir_at(dst, &name, 0o700)? {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Destination is taken"));
+                }
+                let subdir = nofollow::open_dir_at(dst, &name)?;
+                copy_dir(&nofollow::open_dir_at(src, &name)?, &subdir, owner)?;
+            }
+            Kind::File => copy_file(&mut nofollow::open_file_at(src, &name)?, dst, &name, owner)?,
+            kind => return Err(io::Error::other(format!("Won't copy a {:?}", kind))),
+        }
+    }
+    let meta = src.metadata()?;
+    set_owner(dst, owner.uid, owner.gid, owner.modes.dir_mode)?;
+    set_times(dst, &meta)
+}
+
+fn copy_file(src: &mut File, dir: &File, name: &OsStr, owner: &CopyOwner) -> io::Result<()> {
+    let meta = src.metadata()?;
+    if !meta.is_file() || meta.nlink() > 1 {
+        return Err(io::Error::other("Changed since it was checked"));
+    }
+    let mut dst = nofollow::create_file_at(dir, name)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        dst.write_all(&buf[..n])?;
+    }
+    dst.sync_all()?;
+
+    // Read back what actually landed, through the name it landed under, and
+    // with its pages dropped from the cache so they come from the disk
+    let landed = dst.metadata()?;
+    let mut readback = nofollow::open_file_at(dir, name)?;
+    let readback_meta = readback.metadata()?;
+    if readback_meta.dev() != landed.dev() || readback_meta.ino() != landed.ino() {
+        return Err(io::Error::other("Copy was swapped out while it was written"));
+    }
+    // SAFETY: posix_fadvise only takes the fd and plain integers.
+    unsafe { libc::posix_fadvise(readback.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    let mut written = Sha256::new();
+    loop {
+        let n = readback.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        written.update(&buf[..n]);
+    }
+    if written.finalize() != hasher.finalize() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Copy doesn't match the original"));
+    }
+
+    set_owner(&dst, owner.uid, owner.gid, owner.modes.file_mode_for(meta.mode()))?;
+    set_times(&dst, &meta)
+}
+
+fn set_times(file: &File, meta: &Metadata) -> io::Result<()> {
+    file.set_times(FileTimes::new().set_accessed(meta.accessed()?).set_modified(meta.modified()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        ffi::CString,
+        fs,
+        os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    const MODES: ModePolicy = ModePolicy { dir_mode: 0o755, file_mode: 0o644 };
+
+    fn copy(src: &Path, dst_parent: &Path, name: &str) -> io::Result<()> {
+        // Copies are for whoever we are
+        let me = fs::metadata(dst_parent).unwrap();
+        let owner = CopyOwner { uid: me.uid(), gid: me.gid(), modes: &MODES };
+        copy_tree(
+            &nofollow::open_dir(src).unwrap(),
+            &nofollow::open_dir(dst_parent).unwrap(),
+            OsStr::new(name),
+            &owner,
+        )
+    }
+
+    #[test]
+    fn copies_keep_contents_times_and_modes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("lecture.srt"), "words").unwrap();
+        fs::write(src.join("sub/run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(src.join("lecture.srt"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(src.join("sub/run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_681_300_000);
+        for path in ["lecture.srt", "sub/run.sh", "sub"] {
+            fs::File::open(src.join(path)).unwrap().set_modified(then).unwrap();
+        }
+
+        copy(&src, tmp.path(), "dst").unwrap();
+        let dst = tmp.path().join("dst");
+        assert_eq!(fs::read_to_string(dst.join("lecture.srt")).unwrap(), "words");
+        assert_eq!(fs::read_to_string(dst.join("sub/run.sh")).unwrap(), "#!/bin/sh");
+        let mode = |path: &str| fs::metadata(dst.join(path)).unwrap().mode() & 0o7777;
+        assert_eq!(mode("lecture.srt"), 0o644);
+        assert_eq!(mode("sub/run.sh"), 0o755);
+        assert_eq!(mode("sub"), 0o755);
+        assert_eq!(mode(""), 0o755);
+        for path in ["lecture.srt", "sub/run.sh", "sub"] {
+            assert_eq!(fs::metadata(dst.join(path)).unwrap().modified().unwrap(), then, "{}", path);
+        }
+        // The source is left as it was
+        assert_eq!(fs::read_to_string(src.join("lecture.srt")).unwrap(), "words");
+    }
+
+    #[test]
+    fn failed_copies_are_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::write(src.join("a/lecture.srt"), "words").unwrap();
+        let fifo = CString::new(src.join("pipe").as_os_str().as_bytes()).unwrap();
+        // SAFETY: fifo is a valid C string for the whole call.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        assert!(copy(&src, tmp.path(), "dst").is_err());
+        assert!(!tmp.path().join("dst").exists());
+        assert!(src.join("a/lecture.srt").exists());
+    }
+
+    #[test]
+    fn hard_linked_sources_are_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(tmp.path().join("somebody-elses"), "secret").unwrap();
+        fs::hard_link(tmp.path().join("somebody-elses"), src.join("lecture.srt")).unwrap();
+        assert!(copy(&src, tmp.path(), "dst").is_err());
+        assert!(!tmp.path().join("dst").exists());
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Context, Result};
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    os::unix::fs::{fchown, PermissionsExt},
+    path::{Component, Path},
+};
+use crate::nofollow;
+
+// Make sure a destination is inside one of the places the user is allowed to
+// get results, and return which one. srvrs doesn't get to put things
+// anywhere it likes just by asking.
+pub fn check<'a>(destination: &Path, roots: &[&'a Path]) -> Result<&'a Path> {
+    if !destination.is_absolute() {
+        return Err(anyhow!("{} is not an absolute path", destination.display()));
+    }
+    if destination.components().any(|c| !matches!(c, Component::RootDir | Component::Normal(_))) {
+        return Err(anyhow!("{} has . or .. in it", destination.display()));
+    }
+    roots
+        .iter()
+        .find(|root| destination.starts_with(root) && destination != **root)
+        .copied()
+        .ok_or_else(|| anyhow!("{} is not somewhere the user can have results", destination.display()))
+}
+
+// Open the directory a destination goes in, creating whatever is missing
+// below base as the user. Base is trusted. Everything under it is opened
+// without following symlinks, so a user can't send us somewhere else by
+// swapping one of their directories for a link.
+pub fn open_parent(base: &Path, destination: &Path, uid: u32, gid: u32, dir_mode: u32) -> Result<File> {
+    let parent = destination.parent().unwrap_or(base);
+    let relative = parent.strip_prefix(base)?;
+    let mut dir = File::open(base).with_context(|| format!("Could not open {}", base.display()))?;
+    let mut path = base.to_path_buf();
+    for component in relative.components() {
+        let name = component.as_os_str();
+        path.push(name);
+        let created = nofollow::mkdir_at(&dir, name, 0o700)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        let next = nofollow::open_dir_at(&dir, name)
+            .with_context(|| format!("{} is in the way", path.display()))?;
+        if created {
+            fchown(&next, Some(uid), Some(gid))?;
+            next.set_permissions(fs::Permissions::from_mode(dir_mode))?;
+        }
+        dir = next;
+    }
+    Ok(dir)
+}
+
+// The name, or the first of name_2, name_3... that isn't taken yet in dir.
+pub fn unused_name(dir: &File, name: &OsStr) -> OsString {
+    let mut candidate = name.to_os_string();
+    let mut n = 1;
+    while nofollow::exists_at(dir, &candidate) {
+        n += 1;
+        candidate = name.to_os_string();
+        candidate.push(format!("_{}", n));
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    fn ids() -> (u32, u32) {
+        // SAFETY: getuid and getgid can't fail.
+        unsafe { (libc::getuid(), libc::getgid()) }
+    }
+
+    #[test]
+    fn makes_whatever_is_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (uid, gid) = ids();
+        let destination = tmp.path().join("alice/srvrs/whisper/result");
+        let parent = open_parent(tmp.path(), &destination, uid, gid, 0o750).unwrap();
+        let made = fs::metadata(tmp.path().join("alice/srvrs/whisper")).unwrap();
+        assert_eq!(parent.metadata().unwrap().ino(), made.ino());
+        assert_eq!(made.mode() & 0o777, 0o750);
+    }
+
+    #[test]
+    fn symlinked_directories_are_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (uid, gid) = ids();
+        let elsewhere = tmp.path().join("elsewhere");
+        fs::create_dir_all(&elsewhere).unwrap();
+        fs::create_dir_all(tmp.path().join("base/alice")).unwrap();
+        symlink(&elsewhere, tmp.path().join("base/alice/srvrs")).unwrap();
+
+        let destination = tmp.path().join("base/alice/srvrs/whisper/result");
+        let e = open_parent(&tmp.path().join("base"), &destination, uid, gid, 0o755).err().unwrap();
+        assert!(format!("{:#}", e).contains("is in the way"), "{:#}", e);
+        assert_eq!(fs::read_dir(&elsewhere).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn destinations_have_to_be_under_a_root() {
+        let home = Path::new("/home/alice");
+        let scratch = Path::new("/scratch/alice");
+        let roots = [home, scratch];
+        assert_eq!(check(Path::new("/home/alice/srvrs/out"), &roots).unwrap(), home);
+        assert!(check(Path::new("/home/alice/../bob/out"), &roots).is_err());
+        assert!(check(Path::new("/home/alice"), &roots).is_err());
+        assert!(check(Path::new("srvrs/out"), &roots).is_err());
+        assert!(check(Path::new("/etc/cron.d/out"), &roots).is_err());
+    }
+}
@@ -1,18 +1,73 @@
-use std::{os::unix::fs::chown, fs::rename};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    io::{self, Read, Seek, SeekFrom, Write},
+    fs::{self, DirBuilder},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher, Config};
 use chrono;
-use log::{info, error, LevelFilter};
-use users::{get_user_by_name, get_group_by_name};
+use log::{info, error, warn};
+use users::{get_user_by_name, get_group_by_name, os::unix::UserExt};
+use crate::{archive, destination, jobs, ledger, manifest, nofollow, ownership, sink, space};
+use crate::archive::Format;
+use crate::copy::{copy_tree, CopyOwner};
+use crate::ledger::{Delivery, Ledger};
+use crate::metrics::{DeliveryMetrics, METRICS_PATH};
+use crate::sink::{DeliverySink, Unavailable};
+use crate::space::DoesNotFit;
+use crate::ownership::{hand_over, set_owner, ModePolicy};
 
 pub struct Distributor {
     pub work_path: String,
     pub destination_base_path: String,
+    pub modes: ModePolicy, // What the delivered files' modes get set to
+    pub group: String, // Group that delivered files belong to
+    pub dead_letter_path: String, // Where results go when they can't be delivered
+    pub srvrs_user: String, // Who job directories have to belong to
+    pub status_path: String, // Where to tell people about held deliveries
+    pub reserve_mib: u64, // Free space to leave on the destination's filesystem
+    pub retry_secs: u64, // How often to try held deliveries again
+    pub hold_hours: u64, // How long to hold a delivery before dead lettering it
+    pub ledger: Ledger, // Deliveries we'll clean up once they expire
+    pub scratch_retention_days: Option<u64>, // How long results are kept in scratch, if not forever
+    pub home_retention_days: Option<u64>, // How long results are kept in homes, if not forever
+    pub retention_warning_days: u64, // How long before removing results to warn people
+    pub sinks: HashMap<String, Box<dyn DeliverySink>>, // Where results can go besides a filesystem
+    pub jobs_path: String, // srvrs's job records, to note where results went
+    pub metrics: Mutex<DeliveryMetrics>, // How deliveries have gone, for srvrs to report
+}
+
+// How often to look for expired deliveries
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Held while delivering, so that `srvrs-distributor retry` and the service
+// don't both go at the same results
+const LOCK_PATH: &str = "/run/srvrs-distributor.lock";
+
+// A delivery we're holding until there's room for it
+struct Held {
+    since: i64, // Unix timestamp of when we first tried
+    reason: String,
+}
+
+// What became of results we tried to deliver
+enum Handled {
+    Delivered,
+    Held(String),
+    DeadLettered(String),
+    Gone, // Somebody else got to them first
 }
 
 impl Distributor {
     pub fn launch(&self) {
-        systemd_journal_logger::init().unwrap();
-        log::set_max_level(LevelFilter::Info);
         info!("Distributor Active.");
         info!("Watching {}. Will move files when file is added.", self.work_path);
         if let Err(e) = self.watch() {
@@ -20,6 +75,123 @@ impl Distributor {
         }  
     }
 
+    // Try delivering dead lettered results, and whatever's waiting in the
+    // work path, again, or just the ones for the given jobs. Dead lettered
+    // results are put back in the work path first, as if srvrs had just
+    // handed them over. Returns how many still couldn't be delivered.
+    pub fn retry(&self, jobs: &[String]) -> usize {
+        let wanted = |job_id: &str| jobs.is_empty() || jobs.iter().any(|j| j == job_id);
+
+        let dirs = self.open_dead_letter_dir(false)
+            .and_then(|dead| Ok((dead, nofollow::open_dir(Path::new(&self.work_path))?)));
+        match dirs {
+            Ok((dead, work_dir)) => {
+                for (parked, job_id) in dead_letters(&dead) {
+                    if !wanted(&job_id) {
+                        continue;
+                    }
+                    let parked_path = Path::new(&self.dead_letter_path).join(&parked);
+                    if nofollow::exists_at(&work_dir, OsStr::new(&job_id)) {
+                        println!("{}: already waiting in {}, leaving {} alone", job_id, self.work_path, parked_path.display());
+                        continue;
+                    }
+                    match nofollow::rename_at(&dead, &parked, &work_dir, OsStr::new(&job_id)) {
+                        Ok(()) => {
+                            let mut reason = parked.clone();
+                            reason.push(".reason");
+                            let _ = nofollow::remove_file_at(&dead, &reason);
+                            info!("Put {} back in {} to try again", parked_path.display(), self.work_path);
+                        }
+                        Err(e) => println!("{}: could not put it back in {}: {}", job_id, self.work_path, e),
+                    }
+                }
+            }
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {}
+            Err(e) => println!("Not retrying dead lettered results: {:#}", e),
+        }
+        let work = self.waiting().into_iter()
+            .filter(|w| wanted(&w.file_name().unwrap_or_default().to_string_lossy()));
+        // The running distributor may get to them first, and dead letter them
+        // again, so remember what was already parked
+        let parked_before: Vec<OsString> = self.open_dead_letter_dir(false)
+            .map(|dead| dead_letters(&dead).into_iter().map(|(parked, _)| parked).collect())
+            .unwrap_or_default();
+
+        let mut failures = 0;
+        let mut held = BTreeMap::new();
+        for entry in work {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy().to_string();
+            match self.handle(&entry, &mut held) {
+                Handled::Delivered => println!("{}: delivered", name),
+                Handled::Gone => {
+                    let parked = self.open_dead_letter_dir(false).ok()
+                        .and_then(|dead| parked_since(&dead, &name, &parked_before));
+                    match parked {
+                        Some(reason) => {
+                            println!("{}: dead lettered again: {}", name, reason);
+                            failures += 1;
+                        }
+                        None => println!("{}: already delivered", name),
+                    }
+                }
+                Handled::Held(reason) => {
+                    println!("{}: still waiting: {}", name, reason);
+                    failures += 1;
+                }
+                Handled::DeadLettered(reason) => {
+                    println!("{}: dead lettered again: {}", name, reason);
+                    failures += 1;
+                }
+            }
+        }
+        failures
+    }
+
+    // Job directories sitting in the work path
+    fn waiting(&self) -> Vec<PathBuf> {
+        let entries = match fs::read_dir(&self.work_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Could not read {}: {}", self.work_path, e);
+                return vec![];
+            }
+        };
+        let mut found: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|e| e.path())
+            .collect();
+        found.sort();
+        found
+    }
+
+    // The dead letter directory, made if it isn't there and `create` says to.
+    // Anybody who could write there could have root write anywhere, so it
+    // has to be a real directory of root's.
+    fn open_dead_letter_dir(&self, create: bool) -> Result<fs::File> {
+        let path = Path::new(&self.dead_letter_path);
+        if create {
+            DirBuilder::new().recursive(true).mode(0o700).create(path)
+                .with_context(|| format!("Could not make {}", path.display()))?;
+        }
+        let dir = nofollow::open_dir(path).with_context(|| format!("Could not open {}", path.display()))?;
+        let meta = dir.metadata()?;
+        if meta.uid() != 0 {
+            return Err(anyhow!("{} belongs to UID {}, not root", path.display(), meta.uid()));
+        }
+        dir.set_permissions(fs::Permissions::from_mode(0o700))?;
+        Ok(dir)
+    }
+
+    fn lock(&self) -> std::io::Result<fs::File> {
+        let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).mode(0o600).open(LOCK_PATH)?;
+        // SAFETY: flock only looks at the fd, which lock keeps open.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(lock)
+    }
+
     fn watch(&self) -> notify::Result<()> {
         let (tx, rx) = std::sync::mpsc::channel();
 
@@ -27,70 +199,578 @@ impl Distributor {
         // You can also access each implementation directly e.g. INotifyWatcher.
         let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
 
-        // Add a path to be watched. All files and directories at that path and
-        // below will be monitored for changes.
-        watcher.watch(self.work_path.as_ref(), RecursiveMode::Recursive)?;
+        // Add a path to be watched. Job directories show up at the top of it
+        // whole, so there's no need to look any deeper.
+        watcher.watch(self.work_path.as_ref(), RecursiveMode::NonRecursive)?;
 
-        for res in rx {
-            match res {
-                Ok(event) => {
+        // Deliveries waiting for room, which get tried again every so often
+        let mut held: BTreeMap<PathBuf, Held> = BTreeMap::new();
+
+        // Anything left from before we started, like while we were down
+        for work in self.waiting() {
+            info!("Found waiting job: {}", work.display());
+            self.handle(&work, &mut held);
+        }
+        self.report(&held);
+        let retry_interval = Duration::from_secs(self.retry_secs);
+        let mut last_retry = Instant::now();
+        self.sweep();
+        let mut last_sweep = Instant::now();
+        loop {
+            match rx.recv_timeout(retry_interval) {
+                Ok(Ok(event)) => {
                     match event.kind {
-                        // Only take action after the file is finished writing.
-                        notify::EventKind::Create(
-                            notify::event::CreateKind::Folder
-                        ) => {
-                            info!("Got new file: {:?}", event);
-                            let file = event.paths[0].display().to_string();
-                            let file_name = event.paths[0].file_name().unwrap().to_string_lossy();
-
-                            // This app will watch /var/srvrs/distributor, which the main app will
-                            // move finished work to in one shot.
-
-                            // When srvrs is finished, move the work directory into the user's scratchdir.
-                            info!("Moving {}'s results to {}", file_name, self.destination_base_path);
-                
-                            // Create user's scratch directory if it doesn't exist
-                            std::fs::create_dir_all(format!(
-                                "{}/{}",
-                                self.destination_base_path,
-                                file_name,
-                            ))?;
-
-                            let file_dest = format!(
-                                "{}/{}/{}_{}",
-                                self.destination_base_path,
-                                file_name,
-                                "srvrs",
-                                chrono::offset::Local::now().timestamp()
-                            );
-
-                            // Move the file
-                            rename(
-                                file,
-                                &file_dest
-                            )?;
-
-                            // Change ownership of file
-                            // FIXME: This doesn't change all ownership!
-                            let owner = file_name.to_string();
-                            let my_uid: u32 = match get_user_by_name(&owner) {
-                                    Some(user) => user.uid(),
-                                    _ => panic!("User not found >:("),
-                                };
-                            let my_gid: u32 = match get_group_by_name("member") {
-                                    Some(group) => group.gid(),
-                                    _ => panic!("Group not found >:("),
-                                };
-
-                            info!("UID: {}, GID: {}", my_uid, my_gid);
-                            chown(file_dest, Some(my_uid), Some(my_gid))?;
+                        // srvrs renames finished job directories in, which
+                        // looks like a move rather than a create.
+                        notify::EventKind::Create(notify::event::CreateKind::Folder)
+                        | notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                            notify::event::RenameMode::To
+                        )) => {
+                            info!("Got new job: {:?}", event);
+                            let work = &event.paths[0];
+                            if fs::symlink_metadata(work).is_ok_and(|m| m.is_dir()) {
+                                self.handle(work, &mut held);
+                                self.report(&held);
+                            }
                         },
                         _ => {}
                     }
                 },
-                Err(e) => error!("watch error: {:?}", e),
+                Ok(Err(e)) => error!("watch error: {:?}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if !held.is_empty() && last_retry.elapsed() >= retry_interval {
+                for work in held.keys().cloned().collect::<Vec<_>>() {
+                    self.handle(&work, &mut held);
+                }
+                self.report(&held);
+                last_retry = Instant::now();
+            }
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.sweep();
+                last_sweep = Instant::now();
             }
         }
         Ok(())
     }
+
+    // This app will watch /var/srvrs/distributor, which the main app will
+    // move finished work to in one shot. Results that don't fit where they're
+    // going, or whose sink can't take them, are held and tried again later.
+    // Whatever else can't be delivered goes to the dead letter directory
+    // instead, so that one bad delivery doesn't stop everybody else's.
+    fn handle(&self, work: &Path, held: &mut BTreeMap<PathBuf, Held>) -> Handled {
+        let _lock = self.lock()
+            .map_err(|e| warn!("Could not lock {}, delivering anyway: {}", LOCK_PATH, e));
+        let now = chrono::offset::Local::now().timestamp();
+        let since = held.remove(work).map(|h| h.since).unwrap_or(now);
+        // srvrs writes the manifest just before handing results over, so the
+        // directory was last modified when it did
+        let handed_over = match fs::symlink_metadata(work) {
+            Ok(meta) => meta.modified().ok(),
+            Err(_) => {
+                info!("{} is gone, somebody else must have delivered it", work.display());
+                return Handled::Gone;
+            }
+        };
+        let handled = match self.deliver(work) {
+            Ok(()) => Handled::Delivered,
+            Err(e) if e.downcast_ref::<DoesNotFit>().is_some() || e.downcast_ref::<Unavailable>().is_some() => {
+                let reason = format!("{:#}", e);
+                if now - since < self.hold_hours as i64 * 3600 {
+                    warn!("Holding {}: {}. Will try again in {}s.", work.display(), reason, self.retry_secs);
+                    held.insert(work.to_path_buf(), Held { since, reason: reason.clone() });
+                    Handled::Held(reason)
+                } else {
+                    let reason = format!("Gave up waiting after {} hours: {}", self.hold_hours, reason);
+                    self.give_up(work, &reason);
+                    Handled::DeadLettered(reason)
+                }
+            }
+            Err(e) => {
+                let reason = format!("{:#}", e);
+                self.give_up(work, &reason);
+                Handled::DeadLettered(reason)
+            }
+        };
+        self.count(&handled, handed_over);
+        handled
+    }
+
+    // Keep count of how deliveries go
+    fn count(&self, handled: &Handled, handed_over: Option<SystemTime>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        match handled {
+            Handled::Delivered => {
+                metrics.attempted("delivered");
+                if let Some(latency) = handed_over.and_then(|t| t.elapsed().ok()) {
+                    metrics.delivered(latency.as_secs_f64());
+                }
+            }
+            Handled::Held(_) => metrics.attempted("held"),
+            Handled::DeadLettered(_) => metrics.attempted("dead_lettered"),
+            Handled::Gone => {}
+        }
+    }
+
+    // Bring `srvrs status`, and the metrics srvrs serves, up to date
+    fn report(&self, held: &BTreeMap<PathBuf, Held>) {
+        self.write_held_notice(held)
+            .unwrap_or_else(|e| error!("Could not update the notice of held deliveries: {}", e));
+        self.metrics.lock().unwrap().write(held.len())
+            .unwrap_or_else(|e| warn!("Could not update {}: {}", METRICS_PATH, e));
+    }
+
+    fn give_up(&self, work: &Path, reason: &str) {
+        error!("Could not deliver {}: {}. Moving it to {}.", work.display(), reason, self.dead_letter_path);
+        if let Err(e) = self.dead_letter(work, reason) {
+            error!("Could not dead letter {}: {:#}", work.display(), e);
+        }
+    }
+
+    // Let people know, through `srvrs status`, that their results are
+    // waiting and why.
+    fn write_held_notice(&self, held: &BTreeMap<PathBuf, Held>) -> Result<()> {
+        if held.is_empty() {
+            return self.write_notice("distributor", None);
+        }
+        let mut notice = String::from("distributor - WAITING:\n");
+        for (work, h) in held {
+            let name = work.file_name().unwrap_or_default().to_string_lossy();
+            notice.push_str(&format!("{}: {}\n", name, h.reason));
+        }
+        self.write_notice("distributor", Some(&notice))
+    }
+
+    // Put a notice in srvrs's status directory, or take it down. srvrs owns
+    // that directory, so nothing in it gets followed.
+    fn write_notice(&self, name: &str, notice: Option<&str>) -> Result<()> {
+        let status_dir = nofollow::open_dir(Path::new(&self.status_path))
+            .with_context(|| format!("Could not open {}", self.status_path))?;
+        match notice {
+            Some(notice) => nofollow::replace_file_at(&status_dir, OsStr::new(name), notice.as_bytes(), 0o644)?,
+            None => {
+                nofollow::remove_file_at(&status_dir, OsStr::new(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Remove deliveries that have expired, and let people know, through
+    // `srvrs status`, about the ones that will soon. Only deliveries in the
+    // ledger are ever removed.
+    fn sweep(&self) {
+        let deliveries = match self.ledger.load() {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                error!("Could not read the ledger at {}: {:#}", self.ledger.path, e);
+                return;
+            }
+        };
+        let now = chrono::offset::Local::now().timestamp();
+        let warn_before = self.retention_warning_days as i64 * 86400;
+        let mut kept = vec![];
+        let mut expiring = vec![];
+        for delivery in deliveries {
+            if delivery.expires > now {
+                if delivery.expires - now <= warn_before {
+                    expiring.push(delivery.clone());
+                }
+                kept.push(delivery);
+                continue;
+            }
+            match ledger::remove(&delivery) {
+                Ok(true) => info!("Removed {}'s expired results at {}", delivery.owner, delivery.path.display()),
+                Ok(false) => info!("{}'s results at {} were moved or removed already", delivery.owner, delivery.path.display()),
+                Err(e) => {
+                    error!("Could not remove {}: {}. Will try again later.", delivery.path.display(), e);
+                    kept.push(delivery);
+                }
+            }
+        }
+        self.ledger.save(&kept)
+            .unwrap_or_else(|e| error!("Could not update the ledger at {}: {:#}", self.ledger.path, e));
+        self.write_expiring_notice(&expiring)
+            .unwrap_or_else(|e| error!("Could not update the notice of expiring results: {}", e));
+    }
+
+    fn write_expiring_notice(&self, expiring: &[Delivery]) -> Result<()> {
+        if expiring.is_empty() {
+            return self.write_notice("retention", None);
+        }
+        // Everybody can read the status directory, so this only says which
+        // jobs, not whose they are or where they went
+        let mut notice = String::from("retention - EXPIRING:\n");
+        for delivery in expiring {
+            let date = chrono::DateTime::from_timestamp(delivery.expires, 0)
+                .map(|d| d.with_timezone(&chrono::offset::Local).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            notice.push_str(&format!("{}'s results will be removed on {}\n", delivery.job_id, date));
+        }
+        self.write_notice("retention", Some(&notice))
+    }
+
+    fn deliver(&self, work: &Path) -> Result<()> {
+        // It has to be a real directory that srvrs made. Everything after
+        // this goes through what we opened here rather than its path, since
+        // srvrs can still change what's at the path.
+        let work_parent = nofollow::open_dir(Path::new(&self.work_path))?;
+        let work_name = work.file_name().unwrap_or_default();
+        let job_dir = nofollow::open_dir_at(&work_parent, work_name).context("Could not open it")?;
+        let job_meta = job_dir.metadata()?;
+        let srvrs_uid = match get_user_by_name(&self.srvrs_user) {
+            Some(user) => user.uid(),
+            None => return Err(anyhow!("There is no user named {}", self.srvrs_user)),
+        };
+        if job_meta.uid() != srvrs_uid {
+            return Err(anyhow!("It belongs to UID {}, not {}", job_meta.uid(), self.srvrs_user));
+        }
+
+        // Work out who it's for before touching anything. The manifest has
+        // to agree with the user database about who that is.
+        let manifest = manifest::read(&job_dir, work)?;
+        info!("{} is {}'s {} of {} ({})", manifest.job_id, manifest.owner, manifest.activity, manifest.input, manifest.outcome);
+        if let Some(error) = &manifest.error {
+            info!("{} went wrong: {}", manifest.job_id, error);
+        }
+        let owner = manifest.owner.clone();
+        let user = match get_user_by_name(&owner) {
+            Some(user) => user,
+            None => return Err(anyhow!("There is no user named {}", owner)),
+        };
+        if user.uid() != manifest.owner_uid {
+            return Err(anyhow!("{} is UID {}, but the manifest says {}", owner, user.uid(), manifest.owner_uid));
+        }
+        let my_uid: u32 = user.uid();
+        let my_gid: u32 = match get_group_by_name(&self.group) {
+            Some(group) => group.gid(),
+            None => return Err(anyhow!("There is no group named {}", self.group)),
+        };
+        info!("UID: {}, GID: {}", my_uid, my_gid);
+
+        let problems = ownership::problems(&job_dir, work)?;
+        if !problems.is_empty() {
+            return Err(anyhow!("Refusing to deliver it: {}", problems.join(", ")));
+        }
+        let copy_owner = CopyOwner { uid: my_uid, gid: my_gid, modes: &self.modes };
+
+        // Results going to a sink don't need anywhere on disk
+        if let Some(sink_name) = &manifest.sink {
+            let Some(sink) = self.sinks.get(sink_name) else {
+                return Err(anyhow!("There is no sink called {}", sink_name));
+            };
+            let location = self.deliver_to_sink(sink.as_ref(), &job_dir, &work_parent, &manifest, &copy_owner)?;
+            info!("Uploaded {}'s results to {}", owner, location);
+            self.remove_original(&work_parent, work_name, work, &job_meta);
+            self.note_delivery(&manifest, &location, srvrs_uid);
+            return Ok(());
+        }
+
+        // Results go wherever srvrs asked, as long as that's in the user's
+        // home or their scratch directory, or by default into their scratch
+        // directory. Whatever's missing on the way gets made as the user.
+        let base = Path::new(&self.destination_base_path);
+        let user_dir = base.join(&owner);
+        let (base, requested) = match &manifest.destination {
+            Some(requested) => {
+                let requested = PathBuf::from(requested);
+                let root = destination::check(&requested, &[user.home_dir(), &user_dir])?;
+                (if root == user_dir { base } else { root }, requested)
+            }
+            None => (base, user_dir.join(&manifest.job_id)),
+        };
+        let parent = destination::open_parent(base, &requested, my_uid, my_gid, self.modes.dir_mode)?;
+
+        // It's kept for whichever is shorter of what the activity and the
+        // destination allow.
+        let destination_days = if base == Path::new(&self.destination_base_path) {
+            self.scratch_retention_days
+        } else {
+            self.home_retention_days
+        };
+        let retention_days = [manifest.retention_days, destination_days].into_iter().flatten().min();
+
+        // Hold it if it won't fit there yet. Archives are always written out
+        // fresh, wherever they're going.
+        let same_filesystem = parent.metadata()?.dev() == job_meta.dev() && manifest.format == Format::Directory;
+        let size = space::tree_size(&job_dir)?;
+        space::check_fits(&parent, size, same_filesystem, my_uid, self.reserve_mib * 1024 * 1024)
+            .with_context(|| format!("{}'s results for {} are waiting", owner, manifest.input))?;
+        let wanted = requested.file_name().unwrap_or_default();
+        let mut name = wanted.to_os_string();
+        if let Some(extension) = manifest.format.extension() {
+            name.push(extension);
+        }
+        let name = destination::unused_name(&parent, &name);
+        let file_dest = requested.with_file_name(&name);
+
+        // Pack it up, if that's how they want it. Nobody else can get at the
+        // archive until it's finished and handed over.
+        if manifest.format != Format::Directory {
+            info!("Packing {}'s results into {}", owner, file_dest.display());
+            let exclude = manifest.exclude_input.then(|| OsStr::new(&manifest.input));
+            let packed = nofollow::create_file_at(&parent, &name)
+                .and_then(|out| {
+                    archive::write(&job_dir, manifest.format, Path::new(wanted), exclude, &copy_owner, &out)?;
+                    Ok(out)
+                });
+            let archive = match packed {
+                Ok(archive) => archive,
+                Err(e) => {
+                    let _ = nofollow::remove_tree_at(&parent, &name);
+                    return Err(self.write_failed(e, &manifest, format!("Could not pack it into {}", file_dest.display())));
+                }
+            };
+            set_owner(&archive, my_uid, my_gid, self.modes.file_mode)?;
+            self.remove_original(&work_parent, work_name, work, &job_meta);
+            self.record(&manifest, base, &file_dest, &archive, retention_days);
+            self.note_delivery(&manifest, &file_dest.display().to_string(), srvrs_uid);
+            return Ok(());
+        }
+
+        // When srvrs is finished, move the work directory into the user's scratchdir.
+        info!("Moving {}'s results to {}", owner, file_dest.display());
+
+        // Move the file
+        match nofollow::rename_at(&work_parent, work_name, &parent, &name) {
+            Ok(()) => {}
+            // It's on another filesystem, so it has to be copied instead
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                info!("{} is on another filesystem, copying it", file_dest.display());
+                if let Err(e) = copy_tree(&job_dir, &parent, &name, &copy_owner) {
+                    return Err(self.write_failed(e, &manifest, format!("Could not copy it to {}", file_dest.display())));
+                }
+                self.remove_original(&work_parent, work_name, work, &job_meta);
+                let delivered = nofollow::open_dir_at(&parent, &name)?;
+                self.record(&manifest, base, &file_dest, &delivered, retention_days);
+                self.note_delivery(&manifest, &file_dest.display().to_string(), srvrs_uid);
+                return Ok(());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Could not move it to {}", file_dest.display())),
+        }
+
+        let delivered = check_moved(&parent, &name, &work_parent, work_name, &job_meta)?;
+
+        // Change ownership of everything in it
+        let failures = hand_over(&delivered, &file_dest, my_uid, my_gid, &self.modes);
+        if failures > 0 {
+            error!("Could not hand over {} entries in {}", failures, file_dest.display());
+        }
+        self.record(&manifest, base, &file_dest, &delivered, retention_days);
+        self.note_delivery(&manifest, &file_dest.display().to_string(), srvrs_uid);
+        Ok(())
+    }
+
+    // Upload results to a sink, under a prefix of their owner's, returning
+    // where they ended up. Archives are packed into a file nobody else can
+    // open first.
+    fn deliver_to_sink(
+        &self,
+        sink: &dyn DeliverySink,
+        job_dir: &fs::File,
+        work_parent: &fs::File,
+        manifest: &manifest::Manifest,
+        copy_owner: &CopyOwner,
+    ) -> Result<String> {
+        let prefix = sink::job_prefix(&manifest.owner, &manifest.job_id);
+        let Some(extension) = manifest.format.extension() else {
+            info!("Uploading {}'s results to {}", manifest.owner, sink.url(&prefix));
+            let count = sink::put_tree(sink, job_dir, &prefix)?;
+            info!("Uploaded {} files", count);
+            return Ok(sink.url(&prefix));
+        };
+        let key = format!("{}{}", prefix, extension);
+        info!("Packing {}'s results to upload as {}", manifest.owner, key);
+        let exclude = manifest.exclude_input.then(|| OsStr::new(&manifest.input));
+        let mut archive = nofollow::temp_file_in(work_parent)
+            .and_then(|out| {
+                archive::write(job_dir, manifest.format, Path::new(&manifest.job_id), exclude, copy_owner, &out)?;
+                Ok(out)
+            })
+            .map_err(|e| self.write_failed(e, manifest, "Could not pack it".to_string()))?;
+        let len = archive.metadata()?.len();
+        archive.seek(SeekFrom::Start(0))?;
+        sink.put(&key, &mut archive, len)
+    }
+
+    // Let srvrs's record of the job say where the results went
+    fn note_delivery(&self, manifest: &manifest::Manifest, location: &str, srvrs_uid: u32) {
+        jobs::note_delivery(&self.jobs_path, &manifest.job_id, srvrs_uid, location)
+            .unwrap_or_else(|e| warn!("Could not note where {} went in its record: {:#}", manifest.job_id, e));
+    }
+
+    // Running out of room halfway through writing results out is worth
+    // waiting out. Anything else isn't.
+    fn write_failed(&self, e: io::Error, manifest: &manifest::Manifest, doing: String) -> anyhow::Error {
+        if matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT)) {
+            let waiting = format!("{}'s results for {} are waiting", manifest.owner, manifest.input);
+            return anyhow::Error::new(DoesNotFit { reason: e.to_string() }).context(waiting);
+        }
+        anyhow::Error::new(e).context(doing)
+    }
+
+    // Once the results are copied or packed up, the original can go, as long
+    // as it's still what we delivered.
+    fn remove_original(&self, work_parent: &fs::File, work_name: &OsStr, work: &Path, job_meta: &fs::Metadata) {
+        match nofollow::open_dir_at(work_parent, work_name) {
+            Ok(original) if same_file(&original, job_meta) => {
+                nofollow::remove_tree_at(work_parent, work_name)
+                    .unwrap_or_else(|e| error!("Could not remove {} after delivering it: {}", work.display(), e));
+            }
+            _ => error!("{} changed while we delivered it, so it was left there", work.display()),
+        }
+    }
+
+    // Put a delivery in the ledger, so it gets cleaned up once it expires.
+    // Results that are kept forever don't need to be in there.
+    fn record(
+        &self,
+        manifest: &manifest::Manifest,
+        root: &Path,
+        path: &Path,
+        delivered: &fs::File,
+        retention_days: Option<u64>,
+    ) {
+        let Some(days) = retention_days else {
+            return;
+        };
+        let now = chrono::offset::Local::now().timestamp();
+        let result = delivered.metadata().map_err(anyhow::Error::from).and_then(|meta| {
+            self.ledger.record(Delivery {
+                job_id: manifest.job_id.clone(),
+                owner: manifest.owner.clone(),
+                root: root.to_path_buf(),
+                path: path.to_path_buf(),
+                dev: meta.dev(),
+                ino: meta.ino(),
+                delivered: now,
+                expires: now + days as i64 * 86400,
+            })
+        });
+        match result {
+            Ok(()) => info!("{} will be kept for {} days", path.display(), days),
+            Err(e) => error!("Could not add {} to the ledger, so it won't be cleaned up: {:#}", path.display(), e),
+        }
+    }
+
+    // Park an undeliverable result, with a note next to it saying why.
+    fn dead_letter(&self, work: &Path, reason: &str) -> Result<()> {
+        let dead = self.open_dead_letter_dir(true)?;
+        let work_dir = nofollow::open_dir(Path::new(&self.work_path))?;
+        let name = work.file_name().unwrap_or_default();
+        let mut parked = name.to_os_string();
+        parked.push(format!("_{}", chrono::offset::Local::now().timestamp()));
+        nofollow::rename_at(&work_dir, name, &dead, &parked)?;
+        parked.push(".reason");
+        nofollow::create_file_at(&dead, &parked)?.write_all(format!("{}\n", reason).as_bytes())?;
+        Ok(())
+    }
+}
+
+// Dead lettered results, by the name they're parked under, and the jobs
+// they're for
+fn dead_letters(dead: &fs::File) -> Vec<(OsString, String)> {
+    let Ok(entries) = nofollow::entries(dead) else {
+        return vec![];
+    };
+    let mut found = vec![];
+    for entry in entries {
+        if nofollow::kind_at(dead, &entry).ok() != Some(nofollow::Kind::Dir) {
+            continue;
+        }
+        // They're parked as <job id>_<timestamp>
+        let name = entry.to_string_lossy().to_string();
+        if let Some((job_id, _)) = name.rsplit_once('_') {
+            let job_id = job_id.to_string();
+            found.push((entry, job_id));
+        }
+    }
+    found.sort();
+    found
+}
+
+// Why job_id was dead lettered, if it was parked since `before` was taken
+fn parked_since(dead: &fs::File, job_id: &str, before: &[OsString]) -> Option<String> {
+    let (mut parked, _) = dead_letters(dead).into_iter()
+        .find(|(parked, j)| j == job_id && !before.contains(parked))?;
+    parked.push(".reason");
+    let mut reason = String::new();
+    match nofollow::open_file_at(dead, &parked).and_then(|mut f| f.read_to_string(&mut reason)) {
+        Ok(_) => Some(reason.trim_end().to_string()),
+        Err(e) => Some(format!("could not read why: {}", e)),
+    }
+}
+
+fn same_file(file: &fs::File, meta: &fs::Metadata) -> bool {
+    file.metadata().is_ok_and(|m| m.dev() == meta.dev() && m.ino() == meta.ino())
+}
+
+// Make sure what we moved to name in parent is what we checked. If not, put
+// it back where it came from so it gets dead lettered.
+fn check_moved(parent: &fs::File, name: &OsStr, work_parent: &fs::File, work_name: &OsStr, job_meta: &fs::Metadata) -> Result<fs::File> {
+    match nofollow::open_dir_at(parent, name) {
+        Ok(delivered) if same_file(&delivered, job_meta) => Ok(delivered),
+        _ => {
+            nofollow::rename_at(parent, name, work_parent, work_name)?;
+            Err(anyhow!("It was swapped for something else while we delivered it"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_results_are_kept_if_they_are_what_was_checked() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("work/job-1")).unwrap();
+        fs::create_dir(tmp.path().join("dest")).unwrap();
+        let work = nofollow::open_dir(&tmp.path().join("work")).unwrap();
+        let dest = nofollow::open_dir(&tmp.path().join("dest")).unwrap();
+        let job_meta = nofollow::open_dir_at(&work, OsStr::new("job-1")).unwrap().metadata().unwrap();
+
+        nofollow::rename_at(&work, OsStr::new("job-1"), &dest, OsStr::new("out")).unwrap();
+        assert!(check_moved(&dest, OsStr::new("out"), &work, OsStr::new("job-1"), &job_meta).is_ok());
+        assert!(tmp.path().join("dest/out").is_dir());
+    }
+
+    #[test]
+    fn swapped_results_are_put_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("work/job-1")).unwrap();
+        fs::create_dir_all(tmp.path().join("work/impostor")).unwrap();
+        fs::write(tmp.path().join("work/impostor/marker"), "").unwrap();
+        fs::create_dir(tmp.path().join("dest")).unwrap();
+        let work = nofollow::open_dir(&tmp.path().join("work")).unwrap();
+        let dest = nofollow::open_dir(&tmp.path().join("dest")).unwrap();
+        let job_meta = nofollow::open_dir_at(&work, OsStr::new("job-1")).unwrap().metadata().unwrap();
+
+        // srvrs swaps something else in at the last moment
+        nofollow::rename_at(&work, OsStr::new("job-1"), &work, OsStr::new("checked")).unwrap();
+        nofollow::rename_at(&work, OsStr::new("impostor"), &work, OsStr::new("job-1")).unwrap();
+        nofollow::rename_at(&work, OsStr::new("job-1"), &dest, OsStr::new("out")).unwrap();
+
+        let e = check_moved(&dest, OsStr::new("out"), &work, OsStr::new("job-1"), &job_meta).err().unwrap();
+        assert!(e.to_string().contains("swapped"), "{}", e);
+        assert!(!tmp.path().join("dest/out").exists());
+        assert!(tmp.path().join("work/job-1/marker").exists());
+    }
+
+    #[test]
+    fn results_parked_again_are_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("job-1_100")).unwrap();
+        fs::write(tmp.path().join("job-1_100.reason"), "Not enough space\n").unwrap();
+        let dead = nofollow::open_dir(tmp.path()).unwrap();
+        let before: Vec<OsString> = dead_letters(&dead).into_iter().map(|(parked, _)| parked).collect();
+        assert_eq!(parked_since(&dead, "job-1", &before), None);
+
+        // The running distributor parks it again while we're retrying
+        fs::create_dir_all(tmp.path().join("job-1_200")).unwrap();
+        fs::write(tmp.path().join("job-1_200.reason"), "No such user\n").unwrap();
+        assert_eq!(parked_since(&dead, "job-1", &before).as_deref(), Some("No such user"));
+        assert_eq!(parked_since(&dead, "job-2", &before), None);
+    }
 }
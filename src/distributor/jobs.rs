@@ -0,0 +1,29 @@
+use anyhow::{anyhow, Result};
+use std::{
+    ffi::OsStr,
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+use crate::nofollow;
+
+// Note in srvrs's record of a job where its results ended up. The record
+// belongs to srvrs, so it's only touched through a file we've opened without
+// following symlinks and checked is srvrs's, and only the one field changes.
+pub fn note_delivery(jobs_path: &str, job_id: &str, srvrs_uid: u32, delivered_to: &str) -> Result<()> {
+    let dir = nofollow::open_dir(Path::new(jobs_path))?;
+    let mut file = nofollow::open_file_rw_at(&dir, OsStr::new(&format!("{}.yaml", job_id)))?;
+    let meta = file.metadata()?;
+    if !meta.is_file() || meta.nlink() > 1 || meta.uid() != srvrs_uid {
+        return Err(anyhow!("The record for {} is not a regular file of srvrs's", job_id));
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut job: serde_yaml::Mapping = serde_yaml::from_str(&contents)?;
+    job.insert("delivered_to".into(), delivered_to.into());
+    let contents = serde_yaml::to_string(&job)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
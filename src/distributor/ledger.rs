@@ -0,0 +1,159 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+use crate::nofollow;
+
+// A delivery we made and will clean up once it expires. Users can move,
+// rename or replace what we delivered, so it's only ours to remove while the
+// same directory or archive is still where we put it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub job_id: String,
+    pub owner: String,
+    pub root: PathBuf, // The home or scratch directory it went under, which we walk down from
+    pub path: PathBuf, // Where it was delivered
+    pub dev: u64,
+    pub ino: u64,
+    pub delivered: i64, // Unix timestamp
+    pub expires: i64, // Unix timestamp of when it gets removed
+}
+
+// Every delivery that's due to be cleaned up, kept somewhere only root can
+// write, so nobody can talk us into removing something we didn't deliver.
+pub struct Ledger {
+    pub path: String,
+}
+
+impl Ledger {
+    pub fn load(&self) -> Result<Vec<Delivery>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(serde_yaml::from_str(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Write it out in one go, so a crash halfway doesn't lose track of
+    // everything.
+    pub fn save(&self, deliveries: &[Delivery]) -> Result<()> {
+        if let Some(dir) = Path::new(&self.path).parent() {
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let tmp = format!("{}.tmp", self.path);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(serde_yaml::to_string(deliveries)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn record(&self, delivery: Delivery) -> Result<()> {
+        let mut deliveries = self.load()?;
+        deliveries.push(delivery);
+        self.save(&deliveries)
+    }
+}
+
+// Remove an expired delivery, if it's still there. Returns false if it's gone
+// or isn't what we delivered any more, in which case it's left alone.
+pub fn remove(delivery: &Delivery) -> io::Result<bool> {
+    let (Some(parent), Some(name)) = (delivery.path.parent(), delivery.path.file_name()) else {
+        return Ok(false);
+    };
+    let gone = |e: &io::Error| {
+        e.kind() == io::ErrorKind::NotFound
+            || matches!(e.raw_os_error(), Some(libc::ELOOP) | Some(libc::ENOTDIR))
+    };
+    let parent = match nofollow::open_under(&delivery.root, parent) {
+        Ok(parent) => parent,
+        Err(e) if gone(&e) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match nofollow::open_file_at(&parent, name) {
+        Ok(delivered) => {
+            let meta = delivered.metadata()?;
+            if meta.dev() != delivery.dev || meta.ino() != delivery.ino {
+                return Ok(false);
+            }
+        }
+        Err(e) if gone(&e) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    nofollow::remove_tree_at(&parent, name)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // Deliver a job's results to <root>/alice/<job>, the way the distributor would
+    fn deliver(root: &Path) -> Delivery {
+        let path = root.join("alice/whisper-20230412T153012345");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("lecture.srt"), "words").unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        Delivery {
+            job_id: "whisper-20230412T153012345".to_string(),
+            owner: "alice".to_string(),
+            root: root.to_path_buf(),
+            path,
+            dev: meta.dev(),
+            ino: meta.ino(),
+            delivered: 0,
+            expires: 0,
+        }
+    }
+
+    #[test]
+    fn removes_what_it_delivered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let delivery = deliver(tmp.path());
+        assert!(remove(&delivery).unwrap());
+        assert!(!delivery.path.exists());
+    }
+
+    #[test]
+    fn leaves_replacements_alone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let delivery = deliver(tmp.path());
+        fs::rename(&delivery.path, tmp.path().join("alice/kept")).unwrap();
+        fs::create_dir(&delivery.path).unwrap();
+        fs::write(delivery.path.join("thesis.tex"), "years of work").unwrap();
+        assert!(!remove(&delivery).unwrap());
+        assert_eq!(fs::read_to_string(delivery.path.join("thesis.tex")).unwrap(), "years of work");
+        assert!(tmp.path().join("alice/kept/lecture.srt").exists());
+    }
+
+    #[test]
+    fn does_not_follow_a_swapped_parent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let delivery = deliver(tmp.path());
+        // The same directory, reached some other way
+        fs::rename(tmp.path().join("alice"), tmp.path().join("elsewhere")).unwrap();
+        symlink(tmp.path().join("elsewhere"), tmp.path().join("alice")).unwrap();
+        assert!(!remove(&delivery).unwrap());
+        assert!(tmp.path().join("elsewhere/whisper-20230412T153012345/lecture.srt").exists());
+    }
+
+    #[test]
+    fn already_removed_deliveries_are_left_alone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let delivery = deliver(tmp.path());
+        fs::remove_dir_all(&delivery.path).unwrap();
+        assert!(!remove(&delivery).unwrap());
+        fs::remove_dir_all(tmp.path().join("alice")).unwrap();
+        assert!(!remove(&delivery).unwrap());
+    }
+}
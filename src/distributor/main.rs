@@ -1,25 +1,111 @@
 #![feature(unix_chown)]
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use std::{collections::HashMap, fs};
 
+pub mod archive;
+pub mod config;
+pub mod copy;
+pub mod destination;
 pub mod distributor;
+pub mod jobs;
+pub mod ledger;
+#[path = "../common/logging.rs"]
+pub mod logging;
+pub mod manifest;
+pub mod metrics;
+#[path = "../common/nofollow.rs"]
+pub mod nofollow;
+pub mod ownership;
+#[path = "../common/prometheus.rs"]
+pub mod prometheus;
+pub mod sink;
+pub mod space;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path where we do our work
-    #[arg(short, long, required=true)]
-    work_path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Path where we put the finished product 
-    #[arg(short, long, required=true)]
-    destination_base_path: String,
+    /// Config file, shared with srvrs
+    #[arg(short, long, default_value = "/etc/srvrs.yaml", global = true)]
+    config_file: String,
+
+    /// Log level, overriding the config file
+    #[arg(long, global = true)]
+    log_level: Option<LevelFilter>,
+
+    /// Where to log, overriding the config file
+    #[arg(long, global = true)]
+    log_to: Option<logging::Backend>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Try delivering dead lettered and waiting results again
+    Retry {
+        /// Job IDs to retry. Everything, if none are given.
+        jobs: Vec<String>,
+    },
 }
 
 fn main() {
     let args = Args::parse();
+    let config = match fs::read_to_string(&args.config_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|c| Ok(serde_yaml::from_str::<config::Config>(&c)?))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not read {}: {:#}", args.config_file, e);
+            std::process::exit(1);
+        }
+    };
+    let dc = config.distributor;
+    let logging_config = dc.logging.clone().with_overrides(args.log_level, args.log_to);
+    if let Err(e) = logging::init(&logging_config, LevelFilter::Info, &HashMap::new()) {
+        eprintln!("Could not set up logging: {:#}", e);
+        std::process::exit(1);
+    }
+    let mut sinks: HashMap<String, Box<dyn sink::DeliverySink>> = HashMap::new();
+    if let Some(s3) = &dc.s3 {
+        match sink::s3::S3Sink::new(&s3.endpoint, &s3.bucket, &s3.region, &s3.prefix) {
+            Ok(s3) => sinks.insert("s3".to_string(), Box::new(s3)),
+            Err(e) => {
+                eprintln!("Could not set up the S3 sink: {:#}", e);
+                std::process::exit(1);
+            }
+        };
+    }
     let service = distributor::Distributor { 
-        work_path: args.work_path,
-        destination_base_path: args.destination_base_path
+        work_path: format!("{}/distributor", config.base_dir),
+        destination_base_path: dc.destination_base_dir,
+        modes: ownership::ModePolicy {
+            dir_mode: dc.dir_mode,
+            file_mode: dc.file_mode,
+        },
+        group: dc.group,
+        dead_letter_path: dc.dead_letter_dir,
+        srvrs_user: dc.srvrs_user,
+        status_path: format!("{}/status", config.base_dir),
+        reserve_mib: dc.reserve_mib,
+        retry_secs: dc.retry_secs,
+        hold_hours: dc.hold_hours,
+        ledger: ledger::Ledger { path: dc.ledger_path },
+        scratch_retention_days: dc.scratch_retention_days,
+        home_retention_days: dc.home_retention_days,
+        retention_warning_days: dc.retention_warning_days,
+        sinks,
+        jobs_path: format!("{}/jobs", config.base_dir),
+        metrics: Default::default(),
     };
-    service.launch();
+    match args.command {
+        Some(Command::Retry { jobs }) => {
+            if service.retry(&jobs) > 0 {
+                std::process::exit(1);
+            }
+        }
+        None => service.launch(),
+    }
 }
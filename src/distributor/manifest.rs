@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+use crate::archive::Format;
+use crate::nofollow::{self, Kind};
+
+// srvrs leaves one of these in every job directory it hands us. Has to agree
+// with srvrs.
+pub const MANIFEST_FILE: &str = ".srvrs-manifest.yaml";
+
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    pub job_id: String, // Also the name of the directory it's in
+    pub owner: String,
+    pub owner_uid: u32,
+    pub activity: String,
+    pub input: String, // Name of the file that was uploaded
+    pub outcome: String, // Succeeded, Failed, OutOfMemory...
+    pub error: Option<String>,
+    pub destination: Option<String>, // Where to deliver it, if not the default
+    #[serde(default)]
+    pub retention_days: Option<u64>, // How long to keep it once delivered, if not forever
+    #[serde(default)]
+    pub format: Format, // Whether to deliver it as is or packed into an archive
+    #[serde(default)]
+    pub exclude_input: bool, // Leave the uploaded file out of the archive
+    #[serde(default)]
+    pub sink: Option<String>, // Somewhere other than a filesystem to deliver it to, like s3
+}
+
+// Read a job directory's manifest, making sure it's really the one for this
+// directory. `path` is where the directory is, for the name and messages.
+pub fn read(dir: &File, path: &Path) -> Result<Manifest> {
+    let name = OsStr::new(MANIFEST_FILE);
+    if nofollow::kind_at(dir, name).map_err(|e| anyhow!("It has no {}: {}", MANIFEST_FILE, e))? != Kind::File {
+        return Err(anyhow!("{} is not a regular file", MANIFEST_FILE));
+    }
+    let mut file = nofollow::open_file_at(dir, name)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() || meta.nlink() > 1 {
+        return Err(anyhow!("{} is not a regular file", MANIFEST_FILE));
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let manifest: Manifest = serde_yaml::from_str(&contents)?;
+    let dir_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if manifest.job_id != dir_name {
+        return Err(anyhow!("Its manifest is for job {}", manifest.job_id));
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::symlink};
+
+    const MANIFEST: &str = "job_id: whisper-1\nowner: alice\nowner_uid: 1000\nactivity: whisper\ninput: talk.mp4\noutcome: Succeeded\nerror: null\ndestination: null\n";
+
+    fn job_dir(tmp: &Path) -> std::path::PathBuf {
+        let dir = tmp.join("whisper-1");
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_a_real_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = job_dir(tmp.path());
+        fs::write(dir.join(MANIFEST_FILE), MANIFEST).unwrap();
+        let manifest = read(&nofollow::open_dir(&dir).unwrap(), &dir).unwrap();
+        assert_eq!(manifest.owner, "alice");
+        assert_eq!(manifest.owner_uid, 1000);
+    }
+
+    #[test]
+    fn symlinked_manifests_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = job_dir(tmp.path());
+        let elsewhere = tmp.path().join("forged.yaml");
+        fs::write(&elsewhere, MANIFEST).unwrap();
+        symlink(&elsewhere, dir.join(MANIFEST_FILE)).unwrap();
+        let e = read(&nofollow::open_dir(&dir).unwrap(), &dir).err().unwrap();
+        assert!(e.to_string().contains("is not a regular file"), "{}", e);
+    }
+
+    #[test]
+    fn hard_linked_manifests_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = job_dir(tmp.path());
+        let elsewhere = tmp.path().join("forged.yaml");
+        fs::write(&elsewhere, MANIFEST).unwrap();
+        fs::hard_link(&elsewhere, dir.join(MANIFEST_FILE)).unwrap();
+        assert!(read(&nofollow::open_dir(&dir).unwrap(), &dir).is_err());
+    }
+
+    #[test]
+    fn manifests_for_other_jobs_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("whisper-2");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(MANIFEST_FILE), MANIFEST).unwrap();
+        assert!(read(&nofollow::open_dir(&dir).unwrap(), &dir).is_err());
+    }
+}
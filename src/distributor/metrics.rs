@@ -0,0 +1,67 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::Path,
+};
+use crate::prometheus::{header, label, sample, Histogram, SECONDS_BUCKETS};
+
+// Where srvrs picks our metrics up to serve along with its own. Has to agree
+// with srvrs. /run is root's, so nobody else can put anything in the way.
+pub const METRICS_PATH: &str = "/run/srvrs-distributor/metrics.prom";
+
+// Every way a delivery attempt can go, so they all show up from the start
+const RESULTS: [&str; 3] = ["delivered", "held", "dead_lettered"];
+
+// How deliveries have gone since the distributor started
+pub struct DeliveryMetrics {
+    attempts: BTreeMap<&'static str, u64>, // Delivery attempts, by how they went
+    latency: Histogram, // From srvrs handing results over to them being delivered
+}
+
+impl Default for DeliveryMetrics {
+    fn default() -> Self {
+        DeliveryMetrics {
+            attempts: RESULTS.iter().map(|r| (*r, 0)).collect(),
+            latency: Histogram::new(SECONDS_BUCKETS),
+        }
+    }
+}
+
+impl DeliveryMetrics {
+    pub fn attempted(&mut self, result: &'static str) {
+        *self.attempts.entry(result).or_default() += 1;
+    }
+
+    pub fn delivered(&mut self, latency_secs: f64) {
+        self.latency.observe(latency_secs);
+    }
+
+    // Write everything out for srvrs, replacing what was there in one go so
+    // it never sees half of it. `held` is how many deliveries are waiting.
+    pub fn write(&self, held: usize) -> io::Result<()> {
+        let mut out = String::new();
+        header(&mut out, "srvrs_distributor_attempts_total", "counter", "Delivery attempts, by how they went");
+        for (result, count) in &self.attempts {
+            sample(&mut out, "srvrs_distributor_attempts_total", &label("result", result), *count as f64);
+        }
+        header(&mut out, "srvrs_distributor_held", "gauge", "Deliveries waiting for room or a sink");
+        sample(&mut out, "srvrs_distributor_held", "", held as f64);
+        header(
+            &mut out,
+            "srvrs_distributor_delivery_latency_seconds",
+            "histogram",
+            "How long after srvrs handed results over they were delivered",
+        );
+        self.latency.render(&mut out, "srvrs_distributor_delivery_latency_seconds", "");
+
+        let path = Path::new(METRICS_PATH);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+        let tmp = dir.join(".metrics.prom.tmp");
+        fs::write(&tmp, out)?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644))?;
+        fs::rename(&tmp, path)
+    }
+}
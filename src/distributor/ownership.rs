@@ -0,0 +1,158 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    os::unix::fs::{fchown, MetadataExt, PermissionsExt},
+    path::Path,
+};
+use log::warn;
+use crate::nofollow::{self, Kind};
+
+// The modes delivered results end up with. Files that their owner could
+// execute keep execute wherever the file mode lets somebody read, like
+// chmod's X.
+#[derive(Debug, Clone, Copy)]
+pub struct ModePolicy {
+    pub dir_mode: u32,
+    pub file_mode: u32,
+}
+
+impl ModePolicy {
+    pub fn file_mode_for(&self, current: u32) -> u32 {
+        if current & 0o100 == 0 {
+            return self.file_mode;
+        }
+        // Shift each read bit down onto its execute bit
+        self.file_mode | ((self.file_mode & 0o444) >> 2)
+    }
+}
+
+// Everything in a result we won't deliver: symlinks, which could point a
+// user at something of ours, hard links, which could be somebody else's
+// file, and device files, FIFOs and sockets. `path` is only for messages.
+pub fn problems(dir: &File, path: &Path) -> io::Result<Vec<String>> {
+    let mut found = vec![];
+    for name in nofollow::entries(dir)? {
+        let entry = path.join(&name);
+        match nofollow::kind_at(dir, &name)? {
+            Kind::Dir => found.extend(problems(&nofollow::open_dir_at(dir, &name)?, &entry)?),
+            Kind::File => {
+                let links = nofollow::open_file_at(dir, &name)?.metadata()?.nlink();
+                if links > 1 {
+                    found.push(format!("{} is hard linked", entry.display()));
+                }
+            }
+            Kind::Symlink => found.push(format!("{} is a symlink", entry.display())),
+            Kind::Device => found.push(format!("{} is a device file", entry.display())),
+            Kind::Other => found.push(format!("{} is a FIFO or socket", entry.display())),
+        }
+    }
+    Ok(found)
+}
+
+// Hand a delivered tree over to its new owner. Everything is chowned and
+// chmoded through a file descriptor opened without following symlinks, and
+// checked again once it's open, so nothing swapped in after `problems` looked
+// gets touched. Anything we can't fix is logged and skipped, so one bad entry
+// doesn't leave the rest of the tree unusable. Returns how many entries that
+// happened to. `path` is only for messages.
+pub fn hand_over(dir: &File, path: &Path, uid: u32, gid: u32, policy: &ModePolicy) -> usize {
+    if let Err(e) = set_owner(dir, uid, gid, policy.dir_mode) {
+        warn!("Could not hand over {}: {}", path.display(), e);
+        return 1;
+    }
+    let names = match nofollow::entries(dir) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Could not read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    let mut failures = 0;
+    for name in names {
+        let entry = path.join(&name);
+        match hand_over_entry(dir, &name, &entry, uid, gid, policy) {
+            Ok(n) => failures += n,
+            Err(e) => {
+                warn!("Could not hand over {}: {}", entry.display(), e);
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
+fn hand_over_entry(
+    dir: &File,
+    name: &OsStr,
+    path: &Path,
+    uid: u32,
+    gid: u32,
+    policy: &ModePolicy,
+) -> io::Result<usize> {
+    match nofollow::kind_at(dir, name)? {
+        Kind::Dir => Ok(hand_over(&nofollow::open_dir_at(dir, name)?, path, uid, gid, policy)),
+        Kind::File => {
+            let file = nofollow::open_file_at(dir, name)?;
+            let meta = file.metadata()?;
+            if !meta.is_file() || meta.nlink() > 1 {
+                return Err(io::Error::other("Changed since it was checked"));
+            }
+            set_owner(&file, uid, gid, policy.file_mode_for(meta.mode()))?;
+            Ok(0)
+        }
+        kind => Err(io::Error::other(format!("Won't touch a {:?}", kind))),
+    }
+}
+
+pub fn set_owner(file: &File, uid: u32, gid: u32, mode: u32) -> io::Result<()> {
+    fchown(file, Some(uid), Some(gid))?;
+    file.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, os::unix::{ffi::OsStrExt, fs::symlink}};
+
+    fn mknod(path: &Path, mode: libc::mode_t, dev: libc::dev_t) -> io::Result<()> {
+        let name = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // SAFETY: name is a valid C string for the whole call.
+        if unsafe { libc::mknod(name.as_ptr(), mode, dev) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn clean_results_have_no_problems() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/out.txt"), "hi").unwrap();
+        fs::write(tmp.path().join("out.srt"), "hi").unwrap();
+        let dir = nofollow::open_dir(tmp.path()).unwrap();
+        assert!(problems(&dir, tmp.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_links_and_special_files_anywhere() {
+        let tmp = tempfile::tempdir().unwrap();
+        let results = tmp.path().join("results");
+        fs::create_dir_all(results.join("deep")).unwrap();
+        fs::write(tmp.path().join("theirs"), "secret").unwrap();
+        symlink("/etc/shadow", results.join("deep/link")).unwrap();
+        fs::hard_link(tmp.path().join("theirs"), results.join("hard")).unwrap();
+        mknod(&results.join("fifo"), libc::S_IFIFO | 0o600, 0).unwrap();
+        // Only root can make device files
+        let device = mknod(&results.join("null"), libc::S_IFCHR | 0o600, libc::makedev(1, 3)).is_ok();
+
+        let dir = nofollow::open_dir(&results).unwrap();
+        let found = problems(&dir, &results).unwrap();
+        let has = |what: &str| found.iter().any(|p| p.ends_with(what));
+        assert!(has("deep/link is a symlink"), "{:?}", found);
+        assert!(has("hard is hard linked"), "{:?}", found);
+        assert!(has("fifo is a FIFO or socket"), "{:?}", found);
+        assert_eq!(has("null is a device file"), device, "{:?}", found);
+        assert_eq!(found.len(), if device { 4 } else { 3 }, "{:?}", found);
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::Result;
+use std::{fmt, fs::File};
+use crate::nofollow::{self, Kind};
+
+pub mod s3;
+
+// Somewhere other than a filesystem that results can be delivered to, like
+// an object store. Sinks only have to know how to store one file; the
+// distributor works out what goes where.
+pub trait DeliverySink {
+    // Store len bytes of file under key, returning the URL it can be found at
+    fn put(&self, key: &str, file: &mut File, len: u64) -> Result<String>;
+
+    // The URL that everything under a key prefix can be found at
+    fn url(&self, prefix: &str) -> String;
+}
+
+// A sink that can't take deliveries right now, which is worth waiting out
+#[derive(Debug)]
+pub struct Unavailable {
+    pub reason: String,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+// Where a job's results go in a sink: under their owner, then the job
+pub fn job_prefix(owner: &str, job_id: &str) -> String {
+    format!("{}/{}", owner, job_id)
+}
+
+// Store everything in a job directory under prefix, laid out the way it is
+// on disk. Returns how many files that was.
+pub fn put_tree(sink: &dyn DeliverySink, dir: &File, prefix: &str) -> Result<usize> {
+    let mut count = 0;
+    for name in nofollow::entries(dir)? {
+        let key = format!("{}/{}", prefix, name.to_string_lossy());
+        match nofollow::kind_at(dir, &name)? {
+            Kind::Dir => count += put_tree(sink, &nofollow::open_dir_at(dir, &name)?, &key)?,
+            Kind::File => {
+                let mut file = nofollow::open_file_at(dir, &name)?;
+                let len = file.metadata()?.len();
+                sink.put(&key, &mut file, len)?;
+                count += 1;
+            }
+            kind => return Err(anyhow::anyhow!("Won't upload a {:?}", kind)),
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::BTreeMap, fs, io::Read, os::unix::fs::symlink};
+
+    // Keeps everything it's given
+    #[derive(Default)]
+    struct MemorySink {
+        objects: RefCell<BTreeMap<String, String>>,
+    }
+
+    impl DeliverySink for MemorySink {
+        fn put(&self, key: &str, file: &mut File, len: u64) -> Result<String> {
+            let mut contents = String::new();
+            file.take(len).read_to_string(&mut contents)?;
+            self.objects.borrow_mut().insert(key.to_string(), contents);
+            Ok(self.url(key))
+        }
+
+        fn url(&self, prefix: &str) -> String {
+            format!("memory:///{}", prefix)
+        }
+    }
+
+    #[test]
+    fn trees_are_stored_under_their_owner_and_job() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("lecture.mp4"), "video").unwrap();
+        fs::create_dir(tmp.path().join("subtitles")).unwrap();
+        fs::write(tmp.path().join("subtitles/lecture.srt"), "words").unwrap();
+        let sink = MemorySink::default();
+        let dir = nofollow::open_dir(tmp.path()).unwrap();
+        let count = put_tree(&sink, &dir, &job_prefix("alice", "whisper-20230412T153012345")).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            sink.objects.into_inner(),
+            BTreeMap::from([
+                ("alice/whisper-20230412T153012345/lecture.mp4".to_string(), "video".to_string()),
+                ("alice/whisper-20230412T153012345/subtitles/lecture.srt".to_string(), "words".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_files_are_stored() {
+        let tmp = tempfile::tempdir().unwrap();
+        symlink("/etc/shadow", tmp.path().join("shadow")).unwrap();
+        let sink = MemorySink::default();
+        let dir = nofollow::open_dir(tmp.path()).unwrap();
+        let e = put_tree(&sink, &dir, &job_prefix("alice", "whisper-20230412T153012345")).err().unwrap();
+        assert!(e.to_string().contains("Won't upload a Symlink"), "{}", e);
+        assert!(sink.objects.into_inner().is_empty());
+    }
+}
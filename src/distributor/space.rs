@@ -0,0 +1,167 @@
+use std::{
+    fmt,
+    fs::File,
+    io,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+};
+use crate::nofollow::{self, Kind};
+
+// From linux/quota.h
+const Q_GETQUOTA: u32 = 0x800007;
+const USRQUOTA: u32 = 0;
+const QIF_BLIMITS: u32 = 1;
+const QIF_SPACE: u32 = 4;
+const QUOTA_BLOCK_SIZE: u64 = 1024;
+
+// A delivery that doesn't fit where it's going yet
+#[derive(Debug)]
+pub struct DoesNotFit {
+    pub reason: String,
+}
+
+impl fmt::Display for DoesNotFit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for DoesNotFit {}
+
+// How much disk a tree takes up, in bytes, going by what's allocated for it
+// rather than how long the files are.
+pub fn tree_size(dir: &File) -> io::Result<u64> {
+    let mut size = dir.metadata()?.blocks() * 512;
+    for name in nofollow::entries(dir)? {
+        size += match nofollow::kind_at(dir, &name)? {
+            Kind::Dir => tree_size(&nofollow::open_dir_at(dir, &name)?)?,
+            Kind::File => nofollow::open_file_at(dir, &name)?.metadata()?.blocks() * 512,
+            _ => 0,
+        };
+    }
+    Ok(size)
+}
+
+// Bytes free for ordinary users on the filesystem a directory is on
+fn free_space(dir: &File) -> io::Result<u64> {
+    // SAFETY: statvfs is plain old data, all zeroes is a valid value.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: stat is valid for writes for the whole call.
+    if unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// How much more a user may put on the filesystem a directory is on, in
+// bytes, or None if they have no limit there. Filesystems without quotas, or
+// that we can't ask (like NFS), count as no limit.
+fn quota_left(dir: &File, uid: u32) -> Option<u64> {
+    // SAFETY: dqblk is plain old data, all zeroes is a valid value.
+    let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+    let cmd = ((Q_GETQUOTA << 8) | USRQUOTA) as libc::c_int;
+    // SAFETY: quota is valid for writes for the whole call.
+    let ret = unsafe { libc::syscall(libc::SYS_quotactl_fd, dir.as_raw_fd(), cmd, uid, &mut quota) };
+    if ret < 0 || quota.dqb_valid & (QIF_BLIMITS | QIF_SPACE) != (QIF_BLIMITS | QIF_SPACE) {
+        return None;
+    }
+    if quota.dqb_bhardlimit == 0 {
+        return None;
+    }
+    Some((quota.dqb_bhardlimit * QUOTA_BLOCK_SIZE).saturating_sub(quota.dqb_curspace))
+}
+
+fn mib(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
+
+// Make sure a delivery of `size` bytes fits in `destination` for `uid`,
+// leaving `reserve` bytes free on the filesystem. Moving within a filesystem
+// doesn't use up any space, but it still counts against the user's quota once
+// it's theirs. Not being able to tell is an error, not something to wait out.
+pub fn check_fits(
+    destination: &File,
+    size: u64,
+    same_filesystem: bool,
+    uid: u32,
+    reserve: u64,
+) -> anyhow::Result<()> {
+    let free = match same_filesystem {
+        true => None,
+        false => Some(
+            free_space(destination).map_err(|e| anyhow::anyhow!("Could not check free space: {}", e))?,
+        ),
+    };
+    fits(size, free, reserve, quota_left(destination, uid))?;
+    Ok(())
+}
+
+// Whether `size` bytes fit in `free` bytes, leaving `reserve` of them, and
+// in however much quota is left. `free` is None for moves, which don't take
+// up any more space.
+fn fits(size: u64, free: Option<u64>, reserve: u64, quota_left: Option<u64>) -> Result<(), DoesNotFit> {
+    if let Some(free) = free {
+        if size + reserve > free {
+            return Err(DoesNotFit {
+                reason: format!("it needs {} MiB, and only {} MiB is free", mib(size), mib(free.saturating_sub(reserve))),
+            });
+        }
+    }
+    if let Some(left) = quota_left {
+        if size > left {
+            return Err(DoesNotFit {
+                reason: format!("it needs {} MiB, and only {} MiB of quota is left", mib(size), mib(left)),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::symlink};
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn trees_are_measured_by_what_they_take_up() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("big"), vec![1; 64 * 1024]).unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("sub/small"), "x").unwrap();
+        let dir = nofollow::open_dir(tmp.path()).unwrap();
+        let size = tree_size(&dir).unwrap();
+        let blocks = |path: &str| fs::metadata(tmp.path().join(path)).unwrap().blocks() * 512;
+        assert_eq!(size, blocks("") + blocks("big") + blocks("sub") + blocks("sub/small"));
+        assert!(size >= 64 * 1024);
+    }
+
+    #[test]
+    fn symlinks_are_not_measured_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("results")).unwrap();
+        symlink("/usr", tmp.path().join("results/usr")).unwrap();
+        let dir = nofollow::open_dir(&tmp.path().join("results")).unwrap();
+        assert_eq!(tree_size(&dir).unwrap(), dir.metadata().unwrap().blocks() * 512);
+    }
+
+    #[test]
+    fn copies_have_to_leave_the_reserve() {
+        assert!(fits(10 * MIB, Some(100 * MIB), 90 * MIB, None).is_ok());
+        let e = fits(10 * MIB, Some(100 * MIB), 91 * MIB, None).err().unwrap();
+        assert_eq!(e.reason, "it needs 10 MiB, and only 9 MiB is free");
+    }
+
+    #[test]
+    fn moves_only_count_against_quota() {
+        assert!(fits(10 * MIB, None, 1024 * MIB, None).is_ok());
+        assert!(fits(10 * MIB, None, 0, Some(10 * MIB)).is_ok());
+        let e = fits(10 * MIB, None, 0, Some(5 * MIB)).err().unwrap();
+        assert_eq!(e.reason, "it needs 10 MiB, and only 5 MiB of quota is left");
+    }
+
+    #[test]
+    fn copies_count_against_quota_too() {
+        assert!(fits(10 * MIB, Some(1024 * MIB), 0, Some(5 * MIB)).is_err());
+    }
+}
@@ -1,48 +1,93 @@
 use anyhow::{anyhow, Result};
-use file_owner::PathExt;
 use log::{error, info, warn};
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use regex::Regex;
 use std::{
     fs,
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
-    process::{Command, Stdio},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
     collections::HashMap,
     os::unix::fs::{chown, PermissionsExt},
+    sync::Mutex,
 };
 use serde::{de, Deserialize};
 use crate::{SRVRS_UID, MEMBERS_GID};
-use crate::gpu::wait_for_device;
+use crate::delivery::{hand_off, render_destination, DeliveryFormat};
+use crate::gpu::{wait_for_device, CdiNaming, GpuDevice, GpuRequest};
+use crate::health::GpuHealthConfig;
+use crate::api::ApiConfig;
+use crate::intake::{self, check_upload, Upload};
+use crate::job::{Job, JobRegistry, Outcome};
+use crate::logging::{self, LoggingConfig};
+use crate::metrics::{self, MetricsConfig};
+use crate::runner::{self, Cancelled, RunContext, RunReport, Runner, RunnerConfig};
+use crate::usage::GpuMemorySampler;
 
 #[derive(Deserialize, Debug)]
 pub struct SrvrsConfig {
     pub base_dir: String,
-    pub activities: HashMap<String, ActivityConfig>
+    pub activities: HashMap<String, ActivityConfig>,
+    #[serde(default)]
+    pub gpu_health: GpuHealthConfig,
+    #[serde(default = "default_max_jobs_per_gpu")]
+    pub max_jobs_per_gpu: usize, // Jobs that declare gpu_memory_mib may share a GPU up to this many
+    #[serde(default)]
+    pub cdi_device_names: CdiNaming, // How the CDI spec names GPUs: uuid or index
+    pub destination: Option<String>, // Where results go, like {home}/srvrs/{activity}/{date}_{input_stem}
+    pub retention_days: Option<u64>, // How long the distributor keeps delivered results around
+    pub delivery_format: Option<DeliveryFormat>, // Whether results come as a directory or an archive
+    #[serde(default)]
+    pub user_delivery_formats: HashMap<String, DeliveryFormat>, // What each user would rather get
+    pub sink: Option<String>, // Deliver to one of the distributor's sinks, like s3, instead of a filesystem
+    #[serde(default)]
+    pub logging: LoggingConfig, // Log level, and where logs go
+    pub metrics: Option<MetricsConfig>, // Where to serve Prometheus metrics, if anywhere
+    pub api: Option<ApiConfig>, // Where to take work and answer questions about it over HTTP, if anywhere
+}
+
+fn default_max_jobs_per_gpu() -> usize {
+    1
 }
 
 // An activity is, simply put, a "thing that SRVRS can do for you."
 #[derive(Deserialize, Debug)]
 pub struct ActivityConfig {
     //pub name: String, // The name of this activity 
-    pub script: String, // Path to script this will run 
+    #[serde(flatten)]
+    pub runner: RunnerConfig, // How the work gets done
     #[serde(deserialize_with = "wants_deserializer")]
     pub wants: Vec<infer::MatcherType>, // The kinds of file the script accepts
     pub gpus: usize, // The amount of GPUs that the service wants
+    pub gpu_memory_mib: Option<u64>, // Roughly how much memory a job needs on each GPU
+    #[serde(default)]
+    pub require_p2p: bool, // Only run on GPUs that can talk peer-to-peer
     pub progress_regex: String, // Regex for caputring status from output
+    pub destination: Option<String>, // Overrides the destination for this activity
+    pub retention_days: Option<u64>, // Overrides how long results are kept for this activity
+    pub delivery_format: Option<DeliveryFormat>, // Overrides the delivery format for this activity
+    pub sink: Option<String>, // Overrides the sink for this activity
+    pub log_file: Option<String>, // Also log everything about this activity here
 }
 
 pub struct Activity {
     pub name: String, // The name of this activity 
-    pub script: String, // Path to script this will run 
+    pub runner: Box<dyn Runner>, // What this will run
     pub wants: Vec<infer::MatcherType>, // The kinds of file the script accepts
-    pub gpus: usize, // The amount of GPUs that the service wants
+    pub gpus: GpuRequest, // The GPUs that the service wants
     pub progress_regex: String, // Regex for caputring status from output
+    pub destination: Option<String>, // Template for where results go, if not the default
+    pub retention_days: Option<u64>, // How long results are kept, if not forever
+    pub delivery_format: DeliveryFormat, // What results come as, unless the user says otherwise
+    pub user_delivery_formats: HashMap<String, DeliveryFormat>, // What each user would rather get
+    pub sink: Option<String>, // Where results go instead of a filesystem, if anywhere
     pub watch_dir: String, // The dir this Activity will watch for work
     pub status_path: String, // The file this Activity will report status 
     pub queue_path: String, // The file this Activity will report queue
     pub work_dir: String, // The dir work is done
     pub distributor_dir: String, // The dir to put finished work in
+    pub jobs_dir: String, // The dir this Activity will record its jobs in
+    pub progress: Mutex<HashMap<String, String>>, // The last progress update from each job that's running
 }
 
 fn wants_deserializer<'de, D>(deserializer: D) -> Result<Vec<infer::MatcherType>, D::Error>
@@ -83,17 +128,20 @@ enum StatusSummary {
 impl Activity {
     // Setup the service and watch the requisite directories
     pub async fn launch(&self) {
+        logging::set_scope(&self.name);
+        metrics::register(&self.name, &self.watch_dir);
         // TODO: Parse script and make sure it's formatted correctly?
         info!(
+            target: &self.name,
             "Watching {}. Will run `{}` when a file is added.",
-            self.name, self.script
+            self.name, self.runner
         );
         self.update_status(
             StatusSummary::IDLE,
             "".to_string()
         );
         if let Err(e) = self.watch() {
-            error!("error: {:?}", e);
+            error!(target: &self.name, "error: {:?}", e);
         } 
     }
 
@@ -114,7 +162,7 @@ impl Activity {
         }
 
         write_status(&self.status_path, &self.name, summary, status)
-            .unwrap_or_else(|_|error!("Could not update status"));
+            .unwrap_or_else(|_|error!(target: &self.name, "Could not update status"));
 
         self.update_queue();
     }
@@ -137,50 +185,36 @@ impl Activity {
         }
 
         write_queue(&self.name, &self.watch_dir, &self.queue_path)
-            .unwrap_or_else(|_| error!("Could not update queue"));
+            .unwrap_or_else(|_| error!(target: &self.name, "Could not update queue"));
     }
 
-    // Run whatever script is attached to the activity and use a regex to try
-    // capturing status updates
-    fn run_script(&self, input: String, gpus: String) -> Result<()> {
-        let script = &self.script;
-        let mut cmd = Command::new(script)
-            .arg(&input)
-            .arg(&gpus)
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        let cmd_stdout = cmd.stdout.as_mut().unwrap();
-        let cmd_stdout_reader = BufReader::new(cmd_stdout);
-        let cmd_stdout_lines = cmd_stdout_reader.lines();
-
-        for line in cmd_stdout_lines {
-            match line {
-                Ok(l) => {
-                    info!("{}", l);
-                    let sus_re = Regex::new(&self.progress_regex);
-                    match sus_re {
-                        Ok(re)  => {
-                            for caps in re.captures_iter(&l) {
-                                //info!("Regex Matched: {}", l);
-                                // https://docs.rs/regex/latest/regex/struct.Regex.html#method.captures
-                                self.update_status(
-                                    StatusSummary::RUNNING,
-                                    caps.get(0).unwrap().as_str().to_string()
-                                );
-                            }
-                        },
-                        Err(bad_re) => {
-                            warn!("Got bad regex: {}", bad_re);
-                        },
-                    }
-                }
-                _ => warn!("Could not read command ouput."),
-            };
-        }
+    // Run whatever is attached to the activity and use a regex to try
+    // capturing status updates.
+    fn run_script(&self, job_id: &str, input: String, gpus: &[GpuDevice]) -> Result<RunReport> {
+        let progress_re = Regex::new(&self.progress_regex)
+            .map_err(|bad_re| warn!(target: &self.name, "Got bad regex: {}", bad_re))
+            .ok();
+        let ctx = RunContext {
+            job_id,
+            input: &input,
+            gpus,
+        };
 
-        cmd.wait().unwrap();
-        Ok(())
+        self.runner.run(&ctx, &mut |l| {
+            info!(target: &self.name, "{}", l);
+            if let Some(re) = &progress_re {
+                for caps in re.captures_iter(l) {
+                    //info!("Regex Matched: {}", l);
+                    // https://docs.rs/regex/latest/regex/struct.Regex.html#method.captures
+                    self.progress.lock().unwrap()
+                        .insert(job_id.to_string(), caps.get(0).unwrap().as_str().to_string());
+                    self.update_status(
+                        StatusSummary::RUNNING,
+                        caps.get(0).unwrap().as_str().to_string()
+                    );
+                }
+            }
+        })
     }
 
     fn watch(&self) -> notify::Result<()> {
@@ -194,6 +228,17 @@ impl Activity {
         // will be watched for changes.
         watcher.watch(self.watch_dir.as_ref(), RecursiveMode::NonRecursive)?;
 
+        // Submissions from before a restart are already there, so nothing
+        // will tell us about them
+        let registry = JobRegistry { dir: self.jobs_dir.clone() };
+        match intake::readmit(&self.name, &self.watch_dir, &registry) {
+            Ok(paths) => for path in paths {
+                info!(target: &self.name, "Picking {} back up", path.display());
+                self.pick_up(&[path]);
+            }
+            Err(e) => error!(target: &self.name, "Could not look for earlier submissions: {}", e),
+        }
+
         for res in rx {
             match res {
                 Ok(event) => {
@@ -204,37 +249,51 @@ impl Activity {
                         notify::EventKind::Access(notify::event::AccessKind::Close(
                             notify::event::AccessMode::Write,
                         )) => {
-                            info!("changed: {:?}", event);
-                            match self.respond(&event.paths) {
-                                Ok(()) => {
-                                        self.update_status(
-                                            StatusSummary::IDLE,
-                                            "".to_string()
-                                        );
-                                    }
-                                Err(e) => {
-                                    error!("Error responding to file: {}", e);
-                                    let condemned_path: String =
-                                        event.paths[0].to_string_lossy().to_string();
-                                    warn!("Deleting {}", &condemned_path);
-                                    fs::remove_file(condemned_path)?;
-                                    self.update_status(
-                                        StatusSummary::ERROR,
-                                        format!("Error responding to file: {}", e)
-                                    );
-                                }
-                            };
+                            info!(target: &self.name, "changed: {:?}", event);
+                            self.pick_up(&event.paths);
                         }
                         _ => {}
                     }
                 }
-                Err(e) => error!("watch error: {:?}", e),
+                Err(e) => error!(target: &self.name, "watch error: {:?}", e),
             }
         }
         Ok(())
     }
 
-    fn respond(&self, files: &Vec<PathBuf>) -> Result<()> {
+    // Run whatever was uploaded, and clean up after it if we can't
+    fn pick_up(&self, paths: &[PathBuf]) {
+        // Submissions can be taken back before we get to them
+        if fs::symlink_metadata(&paths[0]).is_err() {
+            info!(target: &self.name, "{} is gone, skipping it", paths[0].display());
+            return;
+        }
+        match self.respond(paths) {
+            Ok(()) => {
+                    self.update_status(
+                        StatusSummary::IDLE,
+                        "".to_string()
+                    );
+                }
+            Err(e) => {
+                error!(target: &self.name, "Error responding to file: {}", e);
+                let condemned_path: String =
+                    paths[0].to_string_lossy().to_string();
+                warn!(target: &self.name, "Deleting {}", &condemned_path);
+                // It's already in the work dir if the job got as far
+                // as running, and that mustn't stop us watching.
+                if let Err(e) = fs::remove_file(&condemned_path) {
+                    warn!(target: &self.name, "Could not delete {}: {}", condemned_path, e);
+                }
+                self.update_status(
+                    StatusSummary::ERROR,
+                    format!("Error responding to file: {}", e)
+                );
+            }
+        };
+    }
+
+    fn respond(&self, files: &[PathBuf]) -> Result<()> {
         // Pick the first file created.
         let file = files[0].display().to_string();
         let file_name = match files[0].file_name() {
@@ -248,12 +307,12 @@ impl Activity {
         };
 
         // Get the owner of the path so we can put our output in their homedir.
-        let owner = match file.owner()?.name()? {
-            Some(name) => name,
-            None => return Err(anyhow!("Could not find an owner for this file")),
-        };
+        // Symlinks, hard links and anything that isn't a plain file are
+        // turned away here.
+        let upload = check_upload(&files[0])?;
+        let owner = upload.owner.clone();
 
-        info!("{} uploaded {}", owner, file);
+        info!(target: &self.name, "{} uploaded {}", owner, file);
 
         // Hacky skip to get stable diffusion working with raw text files.
         if !(self.wants[0] == infer::MatcherType::Text && self.wants.len() == 1) {
@@ -272,39 +331,155 @@ impl Activity {
                     kind.mime_type()
                 ))
             }
-            info!("{} is a {:?}", &file, kind.matcher_type());
+            info!(target: &self.name, "{} is a {:?}", &file, kind.matcher_type());
+        }
+
+        // Keep a record of the job, whichever way it goes.
+        let registry = JobRegistry { dir: self.jobs_dir.clone() };
+        let mut job = Job::new(&self.name, &owner, &file_name);
+        // Submissions through the API were already told their job's ID
+        if let Some(job_id) = &upload.job_id {
+            job.id = job_id.clone();
         }
+        registry.save(&job)
+            .unwrap_or_else(|e| error!(target: &self.name, "Could not record job {}: {}", job.id, e));
+        metrics::job_started(&self.name);
 
+        let file_work_dir = format!("{}/{}", self.work_dir, job.id);
+        let result = self.run_job(&mut job, &upload, &file, &file_name, &file_work_dir);
+
+        job.finish(&result);
+        metrics::job_finished(&self.name, &job.outcome);
+        self.progress.lock().unwrap().remove(&job.id);
+        runner::forget(&job.id);
+        registry.save(&job)
+            .unwrap_or_else(|e| error!(target: &self.name, "Could not record job {}: {}", job.id, e));
+
+        // Once the input has made it to the work directory, the user gets
+        // back whatever is there, whichever way the job went.
+        if Path::new(&file_work_dir).exists() {
+            info!(target: &self.name, "Moving to distributor");
+            self.update_status(
+                StatusSummary::CLEANUP,
+                "Moving to distributor...".to_string()
+            );
+            // If we can't work out where it should go, it still goes to the
+            // default place.
+            let destination = self.destination.as_ref().and_then(|template| {
+                render_destination(template, &job, &file_prefix)
+                    .map_err(|e| warn!(target: &self.name, "Could not set a destination for {}: {}", job.id, e))
+                    .ok()
+            });
+            let format = self.user_delivery_formats.get(&job.owner).unwrap_or(&self.delivery_format);
+            let handed_off = hand_off(
+                &job,
+                &file_work_dir,
+                &self.distributor_dir,
+                destination,
+                self.retention_days,
+                format,
+                self.sink.clone(),
+            );
+            handed_off.unwrap_or_else(|e| error!(target: &self.name, "Could not hand {} to the distributor: {}", job.id, e));
+        }
+        result
+    }
+
+    fn run_job(
+        &self,
+        job: &mut Job,
+        upload: &Upload,
+        file: &str,
+        file_name: &str,
+        file_work_dir: &str,
+    ) -> Result<()> {
         // Wait for a GPU to be free
-        let gpus = wait_for_device(self.gpus)?;
+        let waiting = Instant::now();
+        let allocation = wait_for_device(&self.gpus, &|| runner::is_cancelled(&job.id));
+        metrics::observe_gpu_wait(&self.name, waiting.elapsed().as_secs_f64());
+        let allocation = allocation?;
+        let devices = allocation.devices.clone();
         
         // Create temp work directory. We'll put the file here, then run the command we
         // were given on it.
-        let file_work_dir = format!("{}/{}_{}", self.work_dir, owner, file_prefix);
-        info!("Creating {} for new user work.", file_work_dir);
-        fs::create_dir(&file_work_dir)?;
+        info!(target: &self.name, "Creating {} for new user work.", file_work_dir);
+        fs::create_dir(file_work_dir)?;
 
         // Move file into temp work directory
         let file_work_path = format!("{}/{}", file_work_dir, file_name);
         fs::rename(file, &file_work_path)?;
+        upload.verify(Path::new(&file_work_path))?;
 
-        info!("Running command: {}", self.script.to_owned());
+        if runner::is_cancelled(&job.id) {
+            return Err(Cancelled.into());
+        }
+        info!(target: &self.name, "Running command: {}", self.runner);
         self.update_status(
             StatusSummary::STARTING,
             "Launching command...".to_string()
         );
 
-        self.run_script(file_work_path, gpus)?;
-
-        // When finished, move the work directory into the distributor directory
-        // so that the distributor can send it to the user.
-        info!("Moving to distributor");
-        self.update_status(
-            StatusSummary::CLEANUP,
-            "Moving to distributor...".to_string()
-        );
-        fs::rename(file_work_dir, format!("{}/{}", self.distributor_dir, owner))?;
+        // Account for the script while it runs
+        let sampler = GpuMemorySampler::start(devices.clone());
+        let started = Instant::now();
+        let result = self.run_script(&job.id, file_work_path, &devices);
+        job.usage.wall_secs = started.elapsed().as_secs_f64();
+        metrics::observe_duration(&self.name, job.usage.wall_secs);
+        job.usage.peak_gpu_memory = sampler.finish();
+        for device in &devices {
+            job.usage.gpu_secs.insert(device.uuid.clone(), job.usage.wall_secs);
+        }
+        let report = result?;
+        job.usage.cpu_secs = report.cpu_secs;
+        if runner::is_cancelled(&job.id) {
+            return Err(Cancelled.into());
+        }
+        if let Some(oom) = report.out_of_memory {
+            return Err(oom.into());
+        }
+        if !report.status.success() {
+            return Err(anyhow!("`{}` exited with {}", self.runner, report.status));
+        }
 
         Ok(())
     }
+
+    // Take in a file somebody sent through the API. It goes through the
+    // watch directory like any other upload.
+    pub fn submit(&self, name: &str, owner: &str, body: &mut dyn Read, len: u64) -> Result<String> {
+        let registry = JobRegistry { dir: self.jobs_dir.clone() };
+        intake::submit(&self.name, &self.watch_dir, &registry, name, owner, body, len)
+    }
+
+    // The last progress update from a job, if it's running
+    pub fn progress(&self, job_id: &str) -> Option<String> {
+        self.progress.lock().unwrap().get(job_id).cloned()
+    }
+
+    // Stop one of this activity's jobs, whether it's still waiting or already
+    // running. Returns false if it isn't either.
+    pub fn cancel(&self, job_id: &str) -> Result<bool> {
+        let registry = JobRegistry { dir: self.jobs_dir.clone() };
+        if let Some(submission) = intake::withdraw(job_id)? {
+            info!(target: &self.name, "Cancelled {} before it started", job_id);
+            let mut job = Job::new(&self.name, &submission.owner, &submission.input);
+            job.id = submission.job_id;
+            job.finish(&Err(Cancelled.into()));
+            registry.save(&job)?;
+            metrics::job_withdrawn(&self.name);
+            self.update_queue();
+            return Ok(true);
+        }
+        match registry.get(job_id) {
+            Ok(job) if job.activity == self.name && job.outcome == Outcome::Running => {
+                info!(target: &self.name, "Cancelling {}", job_id);
+                runner::mark_cancelled(job_id);
+                // If there's nothing to stop yet, it'll stop before it starts
+                self.runner.cancel(job_id)
+                    .unwrap_or_else(|e| warn!(target: &self.name, "Could not stop {}: {}", job_id, e));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
 }
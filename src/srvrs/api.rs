@@ -0,0 +1,496 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read},
+    net::{TcpListener, TcpStream},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use users::{get_user_by_name, get_user_by_uid};
+use crate::activity::Activity;
+use crate::http::{self, Request, Stream};
+use crate::job::{Job, JobRegistry, Outcome};
+use crate::nofollow::{self, Kind};
+
+// How long a connection can sit there without sending anything
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A local HTTP API for handing srvrs work and keeping up with it, for things
+// that would rather not scp files around. Over a unix socket, callers are
+// whoever they're running as. Over TCP, which has to stay on this machine,
+// they need a token.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiConfig {
+    pub socket: Option<String>, // Unix socket to listen on, like /run/srvrs/api.sock
+    pub listen: Option<String>, // Loopback address to listen on, like 127.0.0.1:9465
+    #[serde(default)]
+    pub tokens: HashMap<String, String>, // The SHA-256, in hex, of each user's token
+}
+
+struct Api {
+    activities: BTreeMap<String, Arc<Activity>>,
+    tokens: HashMap<String, String>, // Users, by their token's hash
+    registry: JobRegistry,
+}
+
+// What we tell people about a job
+#[derive(Serialize)]
+struct JobStatus {
+    id: String,
+    activity: String,
+    owner: String,
+    input: String,
+    state: &'static str, // queued, running, or how it finished
+    started: Option<i64>,
+    finished: Option<i64>,
+    error: Option<String>,
+    progress: Option<String>, // The last progress update, while it's running
+    delivered_to: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ActivityInfo {
+    name: String,
+    wants: Vec<String>, // The kinds of file it takes
+    gpus: usize,
+}
+
+// Start listening, in the background, wherever the config says to
+pub fn serve(config: ApiConfig, activities: Vec<Arc<Activity>>, jobs_dir: String) {
+    let api = Arc::new(Api {
+        activities: activities.into_iter().map(|a| (a.name.clone(), a)).collect(),
+        tokens: config.tokens.iter().map(|(user, hash)| (hash.to_ascii_lowercase(), user.clone())).collect(),
+        registry: JobRegistry { dir: jobs_dir },
+    });
+    if let Some(path) = &config.socket {
+        match listen_unix(path) {
+            Ok(listener) => {
+                info!("Serving the API on {}", path);
+                let api = api.clone();
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let api = api.clone();
+                                thread::spawn(move || api.answer_unix(stream));
+                            }
+                            Err(e) => warn!("Could not accept an API connection: {}", e),
+                        }
+                    }
+                });
+            }
+            Err(e) => warn!("Cannot serve the API on {}: {:#}", path, e),
+        }
+    }
+    if let Some(address) = &config.listen {
+        match listen_tcp(address) {
+            Ok(listener) => {
+                info!("Serving the API on http://{}", address);
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let api = api.clone();
+                                thread::spawn(move || api.answer_tcp(stream));
+                            }
+                            Err(e) => warn!("Could not accept an API connection: {}", e),
+                        }
+                    }
+                });
+            }
+            Err(e) => warn!("Cannot serve the API on {}: {:#}", address, e),
+        }
+    }
+}
+
+// Anybody can connect, since we know who they are when they do
+fn listen_unix(path: &str) -> Result<UnixListener> {
+    // Clear out the socket from last time, but nothing else
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(anyhow!("{} is in the way", path)),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+// Tokens are all that stand between the network and srvrs, so they're only
+// good on this machine
+fn listen_tcp(address: &str) -> Result<TcpListener> {
+    http::listen_loopback(address)
+}
+
+// Who's on the other end of a unix socket, according to the kernel
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    // SAFETY: ucred is plain old data, all zeroes is a valid value.
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes, and len is cred's size.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Job IDs end up in paths, so only take ones that look like ours
+fn valid_job_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+fn respond_json<T: Serialize>(stream: &mut dyn Stream, status: &str, value: &T) -> io::Result<()> {
+    let body = serde_json::to_vec(value).map_err(io::Error::other)?;
+    http::respond(stream, status, "application/json", &[], &body)
+}
+
+fn respond_error(stream: &mut dyn Stream, status: &str, message: &str) -> io::Result<()> {
+    respond_json(stream, status, &HashMap::from([("error", message)]))
+}
+
+impl Api {
+    fn answer_unix(&self, mut stream: UnixStream) {
+        let user = peer_uid(&stream)
+            .ok()
+            .and_then(get_user_by_uid)
+            .map(|user| user.name().to_string_lossy().to_string());
+        let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+        self.answer(&mut stream, |_| user.clone());
+    }
+
+    fn answer_tcp(&self, mut stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+        self.answer(&mut stream, |request| {
+            let token = request.header("authorization")?.strip_prefix("Bearer ")?;
+            self.tokens.get(&hex(&Sha256::digest(token.trim().as_bytes()))).cloned()
+        });
+    }
+
+    // Work out who's asking, then do what they asked
+    fn answer(&self, stream: &mut dyn Stream, who: impl Fn(&Request) -> Option<String>) {
+        let request = match http::read_request(stream) {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = respond_error(stream, "400 Bad Request", &e.to_string());
+                return;
+            }
+        };
+        let result = match who(&request) {
+            Some(user) => self.route(stream, &request, &user),
+            None => respond_error(stream, "401 Unauthorized", "Who are you?"),
+        };
+        if let Err(e) = result {
+            warn!("Could not answer {} {}: {}", request.method, request.path, e);
+        }
+    }
+
+    fn route(&self, stream: &mut dyn Stream, request: &Request, user: &str) -> io::Result<()> {
+        let parts: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), parts.as_slice()) {
+            ("GET", ["activities"]) => self.list_activities(stream),
+            ("POST", ["activities", name, "jobs"]) => self.submit(stream, request, user, name),
+            ("GET", ["jobs"]) => self.list_jobs(stream, user),
+            ("GET", ["jobs", id]) => match self.find(id, user) {
+                Some(job) => respond_json(stream, "200 OK", &self.status(job)),
+                None => respond_error(stream, "404 Not Found", "No such job"),
+            },
+            ("DELETE", ["jobs", id]) => self.cancel(stream, user, id),
+            ("GET", ["jobs", id, "results"]) => match self.find(id, user) {
+                Some(job) if job.outcome == Outcome::Queued => respond_error(stream, "409 Conflict", "It hasn't run yet"),
+                Some(job) => send_results(stream, &job),
+                None => respond_error(stream, "404 Not Found", "No such job"),
+            },
+            _ => respond_error(stream, "404 Not Found", "Nothing here"),
+        }
+    }
+
+    fn list_activities(&self, stream: &mut dyn Stream) -> io::Result<()> {
+        let activities: Vec<_> = self.activities.values()
+            .map(|a| ActivityInfo {
+                name: a.name.clone(),
+                wants: a.wants.iter().map(|w| format!("{:?}", w)).collect(),
+                gpus: a.gpus.count,
+            })
+            .collect();
+        respond_json(stream, "200 OK", &activities)
+    }
+
+    // Files come in as the whole body, with their name in the query, like
+    // POST /activities/whisper/jobs?name=lecture.mp4
+    fn submit(&self, stream: &mut dyn Stream, request: &Request, user: &str, activity: &str) -> io::Result<()> {
+        let Some(activity) = self.activities.get(activity) else {
+            return respond_error(stream, "404 Not Found", "No such activity");
+        };
+        let Some(name) = request.query("name") else {
+            return respond_error(stream, "400 Bad Request", "Say what the file is called with ?name=");
+        };
+        let Some(len) = request.content_length() else {
+            return respond_error(stream, "411 Length Required", "Say how big the file is with Content-Length");
+        };
+        let result = activity.submit(name, user, &mut request.body(stream), len);
+        match result {
+            Ok(job_id) => {
+                info!(target: &activity.name, "{} submitted {} to {} as {}", user, name, activity.name, job_id);
+                respond_json(stream, "201 Created", &HashMap::from([("id", job_id)]))
+            }
+            Err(e) => respond_error(stream, "400 Bad Request", &format!("{:#}", e)),
+        }
+    }
+
+    fn list_jobs(&self, stream: &mut dyn Stream, user: &str) -> io::Result<()> {
+        match self.registry.all() {
+            Ok(recorded) => {
+                let jobs: Vec<_> = recorded.into_iter()
+                    .filter(|j| j.owner == user)
+                    .map(|j| self.status(j))
+                    .collect();
+                respond_json(stream, "200 OK", &jobs)
+            }
+            Err(e) => respond_error(stream, "500 Internal Server Error", &e.to_string()),
+        }
+    }
+
+    fn cancel(&self, stream: &mut dyn Stream, user: &str, id: &str) -> io::Result<()> {
+        let activity = match self.find(id, user) {
+            Some(job) if matches!(job.outcome, Outcome::Queued | Outcome::Running) => job.activity,
+            Some(_) => return respond_error(stream, "409 Conflict", "It's already finished"),
+            None => return respond_error(stream, "404 Not Found", "No such job"),
+        };
+        let Some(activity) = self.activities.get(&activity) else {
+            return respond_error(stream, "409 Conflict", "Its activity isn't running");
+        };
+        match activity.cancel(id) {
+            Ok(true) => {
+                info!(target: &activity.name, "{} cancelled {}", user, id);
+                respond_json(stream, "202 Accepted", &HashMap::from([("id", id)]))
+            }
+            Ok(false) => respond_error(stream, "409 Conflict", "It's already finished"),
+            Err(e) => respond_error(stream, "500 Internal Server Error", &format!("{:#}", e)),
+        }
+    }
+
+    // Find one of a user's jobs. Everybody else's look like they don't exist.
+    fn find(&self, id: &str, user: &str) -> Option<Job> {
+        if !valid_job_id(id) {
+            return None;
+        }
+        self.registry.get(id).ok()
+            .filter(|job| job.owner == user)
+    }
+
+    fn status(&self, job: Job) -> JobStatus {
+        JobStatus {
+            progress: match job.outcome {
+                Outcome::Running => self.activities.get(&job.activity).and_then(|a| a.progress(&job.id)),
+                _ => None,
+            },
+            state: job.outcome.label(),
+            // Queued jobs haven't been picked up yet
+            started: (job.outcome != Outcome::Queued).then_some(job.started),
+            id: job.id,
+            activity: job.activity,
+            owner: job.owner,
+            input: job.input,
+            finished: job.finished,
+            error: job.error,
+            delivered_to: job.delivered_to,
+        }
+    }
+}
+
+// Send back a job's results from wherever they were delivered. srvrs reads
+// them as itself, so only what belongs to the job's owner is sent, which
+// they could read anyway.
+fn send_results(stream: &mut dyn Stream, job: &Job) -> io::Result<()> {
+    let Some(location) = &job.delivered_to else {
+        return respond_error(stream, "409 Conflict", "They haven't been delivered yet");
+    };
+    // Results in a bucket can be fetched from there
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return http::respond(stream, "303 See Other", "text/plain", &[("Location", location)], location.as_bytes());
+    }
+    let Some(uid) = get_user_by_name(&job.owner).map(|u| u.uid()) else {
+        return respond_error(stream, "404 Not Found", "Their owner is gone");
+    };
+    let path = Path::new(location);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return respond_error(stream, "404 Not Found", "They're gone");
+    };
+    let opened = nofollow::open_dir(parent).and_then(|dir| {
+        let kind = nofollow::kind_at(&dir, name)?;
+        Ok((dir, kind))
+    });
+    let download = |extension: &str| format!("attachment; filename=\"{}{}\"", name.to_string_lossy().replace('"', ""), extension);
+    match opened {
+        Ok((dir, Kind::File)) => {
+            let file = nofollow::open_file_at(&dir, name)?;
+            let meta = file.metadata()?;
+            if meta.uid() != uid || !meta.is_file() {
+                return respond_error(stream, "404 Not Found", "They're gone");
+            }
+            let length = meta.len().to_string();
+            http::respond_unsized(
+                stream,
+                "200 OK",
+                "application/octet-stream",
+                &[("Content-Length", &length), ("Content-Disposition", &download(""))],
+            )?;
+            io::copy(&mut file.take(meta.len()), stream)?;
+            Ok(())
+        }
+        Ok((dir, Kind::Dir)) => {
+            let results = nofollow::open_dir_at(&dir, name)?;
+            if results.metadata()?.uid() != uid {
+                return respond_error(stream, "404 Not Found", "They're gone");
+            }
+            // Directories are sent as a tarball, made as it goes
+            http::respond_unsized(stream, "200 OK", "application/x-tar", &[("Content-Disposition", &download(".tar"))])?;
+            let mut tar = tar::Builder::new(stream);
+            add_to_tar(&mut tar, &results, Path::new(name), uid)?;
+            tar.finish()
+        }
+        _ => respond_error(stream, "404 Not Found", "They're gone"),
+    }
+}
+
+// Add a directory, and everything in it that belongs to `uid`, to a tarball.
+// Anything that isn't a plain file or directory is left out.
+fn add_to_tar<W: io::Write>(tar: &mut tar::Builder<W>, dir: &File, path: &Path, uid: u32) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&dir.metadata()?, tar::HeaderMode::Complete);
+    tar.append_data(&mut header, path, io::empty())?;
+    for name in nofollow::entries(dir)? {
+        let entry = path.join(&name);
+        match nofollow::kind_at(dir, &name)? {
+            Kind::Dir => {
+                let sub = nofollow::open_dir_at(dir, &name)?;
+                if sub.metadata()?.uid() == uid {
+                    add_to_tar(tar, &sub, &entry, uid)?;
+                }
+            }
+            Kind::File => {
+                let file = nofollow::open_file_at(dir, OsStr::new(&name))?;
+                let meta = file.metadata()?;
+                if meta.uid() != uid || !meta.is_file() {
+                    continue;
+                }
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata_in_mode(&meta, tar::HeaderMode::Complete);
+                tar.append_data(&mut header, &entry, file.take(meta.len()))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::Shutdown};
+
+    // An API with one of alice's jobs in its registry
+    fn api_with_a_job(jobs_dir: &Path) -> (Api, Job) {
+        let job = Job::new("whisper", "alice", "lecture.mp4");
+        fs::write(jobs_dir.join(format!("{}.yaml", job.id)), serde_yaml::to_string(&job).unwrap()).unwrap();
+        let api = Api {
+            activities: BTreeMap::new(),
+            tokens: HashMap::from([(hex(&Sha256::digest(b"alices-token")), "alice".to_string())]),
+            registry: JobRegistry { dir: jobs_dir.to_string_lossy().to_string() },
+        };
+        (api, job)
+    }
+
+    // Route one request as user, returning the status line of the response
+    fn route_as(api: &Api, user: &str, method: &str, path: &str) -> String {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        write!(client, "{} {} HTTP/1.1\r\n\r\n", method, path).unwrap();
+        let request = http::read_request(&mut server).unwrap();
+        api.route(&mut server, &request, user).unwrap();
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    // Answer one request over TCP, returning the status line of the response
+    fn answer_tcp_with(api: &Api, authorization: Option<&str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write!(client, "GET /activities HTTP/1.1\r\n").unwrap();
+        if let Some(authorization) = authorization {
+            write!(client, "Authorization: {}\r\n", authorization).unwrap();
+        }
+        write!(client, "\r\n").unwrap();
+        let (server, _) = listener.accept().unwrap();
+        api.answer_tcp(server);
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn job_ids_have_to_look_like_ours() {
+        assert!(valid_job_id("whisper-20230412T153012345"));
+        assert!(valid_job_id("stable_diffusion-20230412T153012345"));
+        for id in ["", ".", "..", ".srvrs-manifest.yaml", "../etc", "a/b", "a b", "a%2e"] {
+            assert!(!valid_job_id(id), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn owners_see_their_jobs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (api, job) = api_with_a_job(tmp.path());
+        assert_eq!(route_as(&api, "alice", "GET", &format!("/jobs/{}", job.id)), "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn other_peoples_jobs_are_not_there() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (api, job) = api_with_a_job(tmp.path());
+        for (method, path) in [
+            ("GET", format!("/jobs/{}", job.id)),
+            ("DELETE", format!("/jobs/{}", job.id)),
+            ("GET", format!("/jobs/{}/results", job.id)),
+        ] {
+            assert_eq!(route_as(&api, "mallory", method, &path), "HTTP/1.1 404 Not Found", "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn tcp_callers_need_a_good_token() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (api, _) = api_with_a_job(tmp.path());
+        assert_eq!(answer_tcp_with(&api, None), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(answer_tcp_with(&api, Some("Bearer not-alices-token")), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(answer_tcp_with(&api, Some("Basic alices-token")), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(answer_tcp_with(&api, Some("Bearer alices-token")), "HTTP/1.1 200 OK");
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use std::{ffi::OsStr, fs, io::Write, path::Path};
+use users::{get_user_by_name, os::unix::UserExt};
+use crate::job::{Job, Outcome};
+use crate::nofollow;
+
+// Every job directory we hand to the distributor has one of these in it,
+// saying whose it is and what happened. It's left in the delivered results.
+pub const MANIFEST_FILE: &str = ".srvrs-manifest.yaml";
+
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub job_id: String, // Also the name of the directory it's in
+    pub owner: String,
+    pub owner_uid: u32,
+    pub activity: String,
+    pub input: String, // Name of the file that was uploaded
+    pub outcome: Outcome,
+    pub error: Option<String>, // What went wrong, if anything
+    pub destination: Option<String>, // Where to deliver it, if not the default
+    pub retention_days: Option<u64>, // How long to keep it once delivered, if not forever
+    pub format: Format, // Whether to deliver it as is or packed into an archive
+    pub exclude_input: bool, // Leave the uploaded file out of the archive
+    pub sink: Option<String>, // Somewhere other than a filesystem to deliver it to, like s3
+}
+
+// What shape results are delivered in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "directory")]
+    Directory,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DeliveryFormat {
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub exclude_input: bool, // Leave the uploaded file out of archives
+}
+
+// Fill in a destination template for a job. Templates can use {home},
+// {user}, {activity}, {job_id}, {input_stem} and {date}. The distributor has
+// the final say on whether the result is somewhere the user may have it.
+pub fn render_destination(template: &str, job: &Job, input_stem: &str) -> Result<String> {
+    let home = match get_user_by_name(&job.owner) {
+        Some(user) => user.home_dir().display().to_string(),
+        None => return Err(anyhow!("There is no user named {}", job.owner)),
+    };
+    let date = match chrono::offset::Local.timestamp_opt(job.started, 0).single() {
+        Some(started) => started.format("%Y-%m-%d").to_string(),
+        None => return Err(anyhow!("Job {} has a bad start time", job.id)),
+    };
+    Ok(template
+        .replace("{home}", &home)
+        .replace("{user}", &job.owner)
+        .replace("{activity}", &job.activity)
+        .replace("{job_id}", &job.id)
+        .replace("{input_stem}", input_stem)
+        .replace("{date}", &date))
+}
+
+// Write the job's manifest into its work directory and move it into the
+// distributor's directory under the job's ID, in one shot.
+pub fn hand_off(
+    job: &Job,
+    work_dir: &str,
+    distributor_dir: &str,
+    destination: Option<String>,
+    retention_days: Option<u64>,
+    format: &DeliveryFormat,
+    sink: Option<String>,
+) -> Result<()> {
+    let owner_uid = match get_user_by_name(&job.owner) {
+        Some(user) => user.uid(),
+        None => return Err(anyhow!("There is no user named {}", job.owner)),
+    };
+    let manifest = Manifest {
+        job_id: job.id.clone(),
+        owner: job.owner.clone(),
+        owner_uid,
+        activity: job.activity.clone(),
+        input: job.input.clone(),
+        outcome: job.outcome.clone(),
+        error: job.error.clone(),
+        destination,
+        retention_days,
+        format: format.format,
+        exclude_input: format.exclude_input,
+        sink,
+    };
+    // The job could have left anything where the manifest goes, a symlink to
+    // somewhere we can write to included, so it's cleared out and the
+    // manifest made fresh without following anything.
+    let dir = nofollow::open_dir(Path::new(work_dir))?;
+    nofollow::remove_file_at(&dir, OsStr::new(MANIFEST_FILE))?;
+    let mut file = nofollow::create_file_at(&dir, OsStr::new(MANIFEST_FILE))?;
+    file.write_all(serde_yaml::to_string(&manifest)?.as_bytes())?;
+    fs::rename(work_dir, format!("{}/{}", distributor_dir, job.id))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn manifests_are_not_written_through_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let victim = tmp.path().join("victim");
+        fs::write(&victim, "precious").unwrap();
+        let job = Job::new("whisper", "root", "lecture.mp4");
+        let work_dir = tmp.path().join("work");
+        fs::create_dir(&work_dir).unwrap();
+        symlink(&victim, work_dir.join(MANIFEST_FILE)).unwrap();
+        let distributor_dir = tmp.path().join("distributor");
+        fs::create_dir(&distributor_dir).unwrap();
+
+        hand_off(
+            &job,
+            work_dir.to_str().unwrap(),
+            distributor_dir.to_str().unwrap(),
+            None,
+            None,
+            &DeliveryFormat::default(),
+            None,
+        ).unwrap();
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious");
+        let manifest = distributor_dir.join(&job.id).join(MANIFEST_FILE);
+        assert!(fs::symlink_metadata(&manifest).unwrap().is_file());
+        assert!(fs::read_to_string(&manifest).unwrap().contains(&job.id));
+    }
+}
@@ -1,9 +1,13 @@
 use nvml_wrapper::{Nvml,error::NvmlError};
 use lazy_static::lazy_static;
 use anyhow::{anyhow, Error};
-use std::{thread::sleep, time};
+use std::{collections::BTreeMap, sync::Mutex, thread::sleep, time};
 use log::{info};
+use serde::Deserialize;
 use itertools::Itertools; // Dependencies are like microplastics. I love microplastics.
+use crate::health::is_healthy;
+use crate::runner::Cancelled;
+use crate::topology::{pick_devices, NvmlTopology};
 
 /*
 TODO: Implement this
@@ -31,37 +35,202 @@ fn main() {
 */
 
 lazy_static! {
-    static ref NVML: Nvml = Nvml::init().unwrap();
+    pub(crate) static ref NVML: Nvml = Nvml::init().unwrap();
+
+    // The srvrs jobs on each device (by UUID), by how much memory (in bytes)
+    // each one said it needs. `None` means the job didn't say, so it gets the
+    // card to itself.
+    static ref ALLOCATIONS: Mutex<BTreeMap<String, Vec<Option<u64>>>> = Mutex::new(BTreeMap::new());
+}
+
+// The kind of device that `nvidia-ctk cdi generate` names GPUs under
+const CDI_KIND: &str = "nvidia.com/gpu";
+
+// How the CDI spec on this machine names GPUs. This has to match the
+// `--device-name-strategy` the spec was generated with.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CdiNaming {
+    #[default]
+    Uuid,
+    Index,
+}
+
+// A GPU. NVML's index is only good until the driver reloads, and doesn't have
+// to agree with CUDA or CDI, so the UUID is what we go by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuDevice {
+    pub index: u32, // NVML's index for the device
+    pub uuid: String, // The device's UUID, like GPU-8f3a...
+    pub cdi_name: String, // What to pass to `podman run --device`
+    pub minor: Option<u32>, // N in /dev/nvidiaN, if the driver told us
 }
 
-fn get_free_devices() -> Result<Vec<usize>, NvmlError>{
+impl GpuDevice {
+    fn new(index: u32, uuid: String, minor: Option<u32>, naming: CdiNaming) -> GpuDevice {
+        let cdi_name = match naming {
+            CdiNaming::Uuid => format!("{}={}", CDI_KIND, uuid),
+            CdiNaming::Index => format!("{}={}", CDI_KIND, index),
+        };
+        GpuDevice { index, uuid, cdi_name, minor }
+    }
+}
+
+// A set of devices handed to a job. They go back in the pool when this is
+// dropped.
+#[derive(Debug)]
+pub struct GpuAllocation {
+    pub devices: Vec<GpuDevice>,
+    memory: Option<u64>,
+}
+
+impl Drop for GpuAllocation {
+    fn drop(&mut self) {
+        let mut allocations = ALLOCATIONS.lock().unwrap();
+        for device in &self.devices {
+            if let Some(jobs) = allocations.get_mut(&device.uuid) {
+                if let Some(i) = jobs.iter().position(|m| *m == self.memory) {
+                    jobs.remove(i);
+                }
+            }
+        }
+    }
+}
+
+// A device can take a job if nothing is running on it at all, or if the only
+// things on it are srvrs jobs that declared their memory footprint, the job
+// cap isn't reached, and both the declared footprints and the memory that's
+// actually free leave room for this one.
+fn get_free_devices(
+    allocations: &BTreeMap<String, Vec<Option<u64>>>,
+    request: &GpuRequest,
+    memory: Option<u64>,
+) -> Result<Vec<GpuDevice>, NvmlError> {
     let nvml_device_count = (*NVML).device_count()?; // Get every GPU in the system
     let mut free_devices = vec![]; // Assume no free devices
     for device_number in 0..nvml_device_count {
         let device = (*NVML).device_by_index(device_number)?;
-        let compute_processes = device.running_compute_processes_v2()?; // Get all processes on current device
-        let graphics_processes = device.running_graphics_processes_v2()?; // Get all processes on current device
-        // Add to the list of free devices if there are no running processes. 
-        if compute_processes.is_empty() && graphics_processes.is_empty() {
-            free_devices.push(device_number as usize);
+        let uuid = device.uuid()?;
+        // Don't hand out cards that are overheating or throwing errors
+        if !is_healthy(&uuid) {
+            continue;
+        }
+        let jobs = allocations.get(&uuid).map(Vec::as_slice).unwrap_or_default();
+        let free = can_take(
+            jobs,
+            memory,
+            request.max_jobs_per_gpu,
+            || {
+                // Processes that aren't ours count too
+                let compute_processes = device.running_compute_processes_v2()?;
+                let graphics_processes = device.running_graphics_processes_v2()?;
+                Ok(!compute_processes.is_empty() || !graphics_processes.is_empty())
+            },
+            || device.memory_info().map(|info| (info.total, info.free)),
+        )?;
+        if free {
+            free_devices.push(GpuDevice::new(device_number, uuid, device.minor_number().ok(), request.cdi_naming));
         }
     }
     Ok(free_devices)
 }
 
-pub fn wait_for_device(requesting: usize) -> Result<String, Error> {
+// Whether a device with `jobs` on it, each with the memory it declared, can
+// take a job that wants `wanted` bytes, if it said. `busy` says whether
+// anything at all is running on the device and `memory` gives its total and
+// free memory. They're only asked when it matters, since they go to NVML.
+fn can_take(
+    jobs: &[Option<u64>],
+    wanted: Option<u64>,
+    max_jobs_per_gpu: usize,
+    busy: impl FnOnce() -> Result<bool, NvmlError>,
+    memory: impl FnOnce() -> Result<(u64, u64), NvmlError>,
+) -> Result<bool, NvmlError> {
+    if jobs.is_empty() {
+        return Ok(!busy()?);
+    }
+    // Somebody's already on it, see if we can share.
+    let Some(wanted) = wanted else {
+        return Ok(false);
+    };
+    if jobs.len() >= max_jobs_per_gpu || jobs.iter().any(|m| m.is_none()) {
+        return Ok(false);
+    }
+    let (total, free) = memory()?;
+    let promised: u64 = jobs.iter().flatten().sum();
+    Ok(promised + wanted <= total && wanted <= free)
+}
+
+// How many srvrs jobs each device (by UUID) has on it right now
+pub fn jobs_per_device() -> BTreeMap<String, usize> {
+    ALLOCATIONS.lock().unwrap().iter().map(|(uuid, jobs)| (uuid.clone(), jobs.len())).collect()
+}
+
+// Total memory in use, in bytes, across a set of devices
+pub fn memory_used(devices: &[GpuDevice]) -> Result<u64, NvmlError> {
+    let mut used = 0;
+    for gpu in devices {
+        let device = (*NVML).device_by_uuid(gpu.uuid.as_str())?;
+        used += device.memory_info()?.used;
+    }
+    Ok(used)
+}
+
+// The comma separated list of device indices that scripts have always gotten
+pub fn device_list(devices: &[GpuDevice]) -> String {
+    devices.iter().map(|d| d.index).format(",").to_string()
+}
+
+// Everything a script needs to find its GPUs, however it plans on using them.
+// CUDA takes UUIDs in CUDA_VISIBLE_DEVICES, which spares bare-metal scripts
+// from guessing at CUDA's ordering.
+pub fn device_env(devices: &[GpuDevice]) -> Vec<(&'static str, String)> {
+    let uuids = devices.iter().map(|d| &d.uuid).format(",").to_string();
+    vec![
+        ("SRVRS_GPU_INDICES", device_list(devices)),
+        ("SRVRS_GPU_UUIDS", uuids.clone()),
+        ("SRVRS_GPU_CDI_DEVICES", devices.iter().map(|d| &d.cdi_name).format(",").to_string()),
+        ("CUDA_VISIBLE_DEVICES", uuids),
+    ]
+}
+
+// What a job needs from the GPUs
+#[derive(Debug, Clone)]
+pub struct GpuRequest {
+    pub count: usize, // How many GPUs
+    pub memory_mib: Option<u64>, // Roughly how much memory on each, if it's willing to share
+    pub max_jobs_per_gpu: usize, // How many jobs may share a GPU
+    pub require_p2p: bool, // Whether the GPUs have to be able to talk to each other directly
+    pub cdi_naming: CdiNaming, // How to name the GPUs we hand out for CDI
+}
+
+// Wait for enough GPUs to be free, giving up if `cancelled` says to
+pub fn wait_for_device(request: &GpuRequest, cancelled: &dyn Fn() -> bool) -> Result<GpuAllocation, Error> {
+    let requesting = request.count;
+    let memory = request.memory_mib.map(|mib| mib * 1024 * 1024);
     let mut timeout_sec = 3600;
     let wait_sec = 2;
     while timeout_sec > 0 {
-        let free_devices = get_free_devices()?;
-        // If we have enough free GPUs, return the first <requesting> GPUs
-        // (this does not account for the GPUs' capabilities.)
-        if free_devices.len() >= requesting {
-            let device_string: String = free_devices[0..requesting].iter().format(",").to_string();
-
-            info!("GPU(s) found! ({})", device_string);
-            return Ok(device_string);
+        if cancelled() {
+            return Err(Cancelled.into());
+        }
+        // Hold the lock until we've claimed our devices so that two
+        // activities can't grab the same room on a card.
+        let mut allocations = ALLOCATIONS.lock().unwrap();
+        let free_devices = get_free_devices(&allocations, request, memory)?;
+        // If we have enough free GPUs, return the <requesting> GPUs with the
+        // best connections between them (this does not account for the GPUs'
+        // capabilities.)
+        let picked = pick_devices(&NvmlTopology, &free_devices, requesting, request.require_p2p)?;
+        if let Some(devices) = picked {
+            for device in &devices {
+                allocations.entry(device.uuid.clone()).or_default().push(memory);
+            }
+
+            info!("GPU(s) found! ({})", devices.iter().map(|d| &d.uuid).format(", "));
+            return Ok(GpuAllocation { devices, memory });
         }
+        drop(allocations);
         info!("Not enough GPUs available. Waiting... ({}s left)", timeout_sec);
         sleep(time::Duration::from_secs(wait_sec));
         timeout_sec -= wait_sec;
@@ -70,4 +239,49 @@ pub fn wait_for_device(requesting: usize) -> Result<String, Error> {
     Err(anyhow!("Could not reserve a GPU in time. Please try again later."))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
 
+    // A 24 GiB card with `free` of it free, and nothing but srvrs on it
+    fn can_take_on(jobs: &[Option<u64>], wanted: Option<u64>, max_jobs_per_gpu: usize, free: u64) -> bool {
+        can_take(jobs, wanted, max_jobs_per_gpu, || Ok(!jobs.is_empty()), || Ok((24 * GIB, free))).unwrap()
+    }
+
+    #[test]
+    fn idle_cards_take_anything() {
+        assert!(can_take_on(&[], None, 1, 24 * GIB));
+        assert!(can_take_on(&[], Some(2 * GIB), 1, 24 * GIB));
+    }
+
+    #[test]
+    fn cards_busy_with_something_else_take_nothing() {
+        assert!(!can_take(&[], Some(GIB), 4, || Ok(true), || Ok((24 * GIB, 20 * GIB))).unwrap());
+    }
+
+    #[test]
+    fn sharing_stops_at_the_job_cap() {
+        assert!(!can_take_on(&[Some(GIB)], Some(GIB), 1, 20 * GIB));
+        assert!(can_take_on(&[Some(GIB)], Some(GIB), 2, 20 * GIB));
+        assert!(!can_take_on(&[Some(GIB), Some(GIB)], Some(GIB), 2, 20 * GIB));
+    }
+
+    #[test]
+    fn undeclared_jobs_get_the_card_to_themselves() {
+        // Neither one that's already there
+        assert!(!can_take_on(&[None], Some(GIB), 4, 20 * GIB));
+        // Nor one that wants to share
+        assert!(!can_take_on(&[Some(GIB)], None, 4, 20 * GIB));
+    }
+
+    #[test]
+    fn shared_jobs_have_to_fit_in_total_and_free_memory() {
+        // What's been promised has to leave room on the card
+        assert!(can_take_on(&[Some(16 * GIB)], Some(8 * GIB), 4, 20 * GIB));
+        assert!(!can_take_on(&[Some(16 * GIB)], Some(9 * GIB), 4, 20 * GIB));
+        // And so does what's actually in use, whatever was promised
+        assert!(!can_take_on(&[Some(GIB)], Some(8 * GIB), 4, 7 * GIB));
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use nvml_wrapper::{
+    bitmasks::{device::ThrottleReasons, event::EventTypes},
+    enum_wrappers::device::{EccCounter, MemoryError, TemperatureSensor},
+    enums::event::XidError,
+    error::NvmlError,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    os::unix::fs::{chown, PermissionsExt},
+    sync::RwLock,
+    thread,
+    time::Duration,
+};
+use crate::gpu::NVML;
+use crate::{SRVRS_UID, MEMBERS_GID};
+
+// Throttling that means the card is in trouble, rather than just idle or
+// capped by a setting.
+const BAD_THROTTLE_REASONS: [(ThrottleReasons, &str); 4] = [
+    (ThrottleReasons::HW_SLOWDOWN, "hardware slowdown"),
+    (ThrottleReasons::HW_THERMAL_SLOWDOWN, "hardware thermal slowdown"),
+    (ThrottleReasons::HW_POWER_BRAKE_SLOWDOWN, "power brake"),
+    (ThrottleReasons::SW_THERMAL_SLOWDOWN, "software thermal slowdown"),
+];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GpuHealthConfig {
+    pub poll_secs: u64, // How often to check on the GPUs
+    pub max_temperature: u32, // Degrees C above which a GPU gets no new work
+    pub cooldown_secs: i64, // How long an ECC or XID error keeps a GPU out of rotation
+}
+
+impl Default for GpuHealthConfig {
+    fn default() -> Self {
+        GpuHealthConfig {
+            poll_secs: 30,
+            max_temperature: 85,
+            cooldown_secs: 600,
+        }
+    }
+}
+
+// What we last saw on a device
+#[derive(Debug, Clone, Default)]
+pub struct DeviceHealth {
+    pub index: u32, // NVML's index for the device, for humans
+    pub temperature: Option<u32>,
+    pub ecc_errors: Option<u64>, // Uncorrected errors since the driver loaded
+    pub problems: Vec<String>, // Reasons it's unhealthy right now
+    pub last_error: Option<String>, // The most recent ECC or XID error
+    pub errors_until: i64, // Unix timestamp at which the last error stops counting against it
+    benched: bool, // Whether we last said it was unhealthy, since errors wear off on their own
+}
+
+impl DeviceHealth {
+    pub fn healthy(&self) -> bool {
+        self.healthy_at(now())
+    }
+
+    fn healthy_at(&self, now: i64) -> bool {
+        self.problems.is_empty() && now >= self.errors_until
+    }
+
+    fn record_error(&mut self, index: u32, uuid: &str, error: String, cooldown_secs: i64, now: i64) {
+        warn!("GPU {} ({}): {}. Excluding it for {}s.", index, uuid, error, cooldown_secs);
+        self.index = index;
+        self.last_error = Some(error);
+        self.errors_until = now + cooldown_secs;
+    }
+
+    // Take in a fresh reading, returning how that changed things, if it did
+    fn update(&mut self, index: u32, uuid: &str, reading: Result<Reading, NvmlError>, config: &GpuHealthConfig, now: i64) -> Option<Change> {
+        let mut problems = vec![];
+        let reading = match reading {
+            Ok(reading) => reading,
+            Err(e) => {
+                problems.push(format!("unreachable ({})", e));
+                Reading::default()
+            }
+        };
+        if let Some(t) = reading.temperature {
+            if t > config.max_temperature {
+                problems.push(format!("too hot ({}C)", t));
+            }
+        }
+        if let Some(reasons) = reading.throttle_reasons {
+            for (reason, description) in BAD_THROTTLE_REASONS {
+                if reasons.contains(reason) {
+                    problems.push(description.to_string());
+                }
+            }
+        }
+        if let (Some(before), Some(after)) = (self.ecc_errors, reading.ecc_errors) {
+            if after > before {
+                self.record_error(
+                    index,
+                    uuid,
+                    format!("{} new uncorrected ECC errors", after - before),
+                    config.cooldown_secs,
+                    now,
+                );
+            }
+        }
+        self.index = index;
+        self.temperature = reading.temperature;
+        self.ecc_errors = reading.ecc_errors;
+        self.problems = problems;
+        match (self.benched, self.healthy_at(now)) {
+            (false, false) => {
+                self.benched = true;
+                Some(Change::Unhealthy(describe(self, now)))
+            }
+            (true, true) => {
+                self.benched = false;
+                Some(Change::Recovered)
+            }
+            _ => None,
+        }
+    }
+}
+
+// How a device's health changed
+#[derive(Debug, PartialEq)]
+enum Change {
+    Unhealthy(String), // Why
+    Recovered,
+}
+
+// What a device says about itself. Anything it can't answer is left out.
+#[derive(Debug, Default)]
+pub struct Reading {
+    pub temperature: Option<u32>,
+    pub ecc_errors: Option<u64>, // Uncorrected errors since the driver loaded, if it has ECC
+    pub throttle_reasons: Option<ThrottleReasons>,
+}
+
+// Where we read how devices are doing. NVML in real life, anything that can
+// answer the question in tests.
+pub trait Readings {
+    fn read(&self, uuid: &str) -> Result<Reading, NvmlError>;
+}
+
+pub struct NvmlReadings;
+
+impl Readings for NvmlReadings {
+    fn read(&self, uuid: &str) -> Result<Reading, NvmlError> {
+        let device = (*NVML).device_by_uuid(uuid)?;
+        Ok(Reading {
+            temperature: device.temperature(TemperatureSensor::Gpu).ok(),
+            // Cards without ECC just don't answer, which is fine.
+            ecc_errors: device.total_ecc_errors(MemoryError::Uncorrected, EccCounter::Volatile).ok(),
+            throttle_reasons: device.current_throttle_reasons().ok(),
+        })
+    }
+}
+
+lazy_static! {
+    // Keyed by device UUID
+    static ref HEALTH: RwLock<BTreeMap<String, DeviceHealth>> = RwLock::new(BTreeMap::new());
+}
+
+fn now() -> i64 {
+    chrono::offset::Local::now().timestamp()
+}
+
+// Devices we haven't looked at yet are given the benefit of the doubt.
+pub fn is_healthy(uuid: &str) -> bool {
+    match HEALTH.read().unwrap().get(uuid) {
+        Some(health) => health.healthy(),
+        None => true,
+    }
+}
+
+// Keep an eye on every GPU in the background, and report on them in
+// `status_path` so that `srvrs status` shows them.
+pub fn monitor_health(config: GpuHealthConfig, status_path: String) {
+    let device_count = match (*NVML).device_count() {
+        Ok(count) => count,
+        Err(e) => {
+            warn!("Cannot monitor GPU health: {}", e);
+            return;
+        }
+    };
+
+    let mut devices = vec![];
+    for index in 0..device_count {
+        match (*NVML).device_by_index(index).and_then(|d| d.uuid()) {
+            Ok(uuid) => devices.push((index, uuid)),
+            Err(e) => warn!("Cannot monitor GPU {}: {}", index, e),
+        }
+    }
+
+    for (index, uuid) in devices.clone() {
+        let cooldown_secs = config.cooldown_secs;
+        thread::spawn(move || {
+            if let Err(e) = watch_xid_errors(index, &uuid, cooldown_secs) {
+                warn!("Cannot watch GPU {} for XID errors: {}", index, e);
+            }
+        });
+    }
+
+    thread::spawn(move || loop {
+        for (index, uuid) in &devices {
+            poll_device(&NvmlReadings, *index, uuid, &config);
+        }
+        write_health_status(&status_path)
+            .unwrap_or_else(|e| warn!("Could not update GPU status: {}", e));
+        thread::sleep(Duration::from_secs(config.poll_secs));
+    });
+}
+
+fn record_error(index: u32, uuid: &str, error: String, cooldown_secs: i64) {
+    let mut health = HEALTH.write().unwrap();
+    health.entry(uuid.to_string()).or_default().record_error(index, uuid, error, cooldown_secs, now());
+}
+
+// XID errors only come in as events, so each device gets a thread that
+// blocks waiting for them.
+fn watch_xid_errors(index: u32, uuid: &str, cooldown_secs: i64) -> Result<()> {
+    let device = (*NVML).device_by_uuid(uuid)?;
+    let set = (*NVML).create_event_set()?;
+    let set = device.register_events(EventTypes::CRITICAL_XID_ERROR, set)?;
+    loop {
+        match set.wait(10_000) {
+            Ok(event) => {
+                let xid = match event.event_data {
+                    Some(XidError::Value(xid)) => xid.to_string(),
+                    _ => "unknown".to_string(),
+                };
+                record_error(index, uuid, format!("XID error {}", xid), cooldown_secs);
+            }
+            Err(NvmlError::Timeout) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn poll_device(readings: &dyn Readings, index: u32, uuid: &str, config: &GpuHealthConfig) {
+    let reading = readings.read(uuid);
+    let mut health = HEALTH.write().unwrap();
+    let device = health.entry(uuid.to_string()).or_default();
+    match device.update(index, uuid, reading, config, now()) {
+        Some(Change::Unhealthy(why)) => warn!("GPU {} ({}) is unhealthy, excluding it: {}", index, uuid, why),
+        Some(Change::Recovered) => info!("GPU {} ({}) has recovered", index, uuid),
+        None => {}
+    }
+}
+
+fn describe(health: &DeviceHealth, now: i64) -> String {
+    let mut reasons = health.problems.clone();
+    if now < health.errors_until {
+        if let Some(error) = &health.last_error {
+            reasons.push(format!("recent {}", error));
+        }
+    }
+    reasons.join(", ")
+}
+
+fn write_health_status(path: &str) -> Result<()> {
+    let mut report = String::from("gpus:\n");
+    let health = HEALTH.read().unwrap();
+    let mut devices: Vec<_> = health.iter().collect();
+    devices.sort_by_key(|(_, h)| h.index);
+    for (uuid, health) in devices {
+        let temperature = match health.temperature {
+            Some(t) => format!("{}C", t),
+            None => "?C".to_string(),
+        };
+        let ecc_errors = match health.ecc_errors {
+            Some(count) => format!("{} ECC errors", count),
+            None => "no ECC".to_string(),
+        };
+        if health.healthy() {
+            report.push_str(&format!("{} {} - HEALTHY: {}, {}\n", health.index, uuid, temperature, ecc_errors));
+        } else {
+            report.push_str(&format!(
+                "{} {} - UNHEALTHY: {}, {} ({})\n",
+                health.index,
+                uuid,
+                temperature,
+                ecc_errors,
+                describe(health, now())
+            ));
+        }
+    }
+
+    let mut sf = fs::File::create(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644))?;
+    chown(path, Some(*SRVRS_UID), Some(*MEMBERS_GID))?;
+    sf.write_all(report.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: GpuHealthConfig = GpuHealthConfig { poll_secs: 30, max_temperature: 85, cooldown_secs: 600 };
+    const NOW: i64 = 1_681_300_000;
+
+    fn reading(temperature: u32, ecc_errors: u64) -> Result<Reading, NvmlError> {
+        Ok(Reading {
+            temperature: Some(temperature),
+            ecc_errors: Some(ecc_errors),
+            throttle_reasons: Some(ThrottleReasons::GPU_IDLE),
+        })
+    }
+
+    // Answers with whatever it was given, once
+    struct FakeReadings(std::cell::RefCell<Option<Result<Reading, NvmlError>>>);
+
+    impl Readings for FakeReadings {
+        fn read(&self, _uuid: &str) -> Result<Reading, NvmlError> {
+            self.0.borrow_mut().take().unwrap()
+        }
+    }
+
+    #[test]
+    fn new_ecc_errors_bench_a_card_until_the_cooldown_is_over() {
+        let mut device = DeviceHealth::default();
+        assert_eq!(device.update(0, "GPU-0", reading(40, 3), &CONFIG, NOW), None);
+        // The errors it came up with don't count against it
+        assert!(device.healthy_at(NOW));
+
+        assert_eq!(
+            device.update(0, "GPU-0", reading(40, 5), &CONFIG, NOW + 30),
+            Some(Change::Unhealthy("recent 2 new uncorrected ECC errors".to_string()))
+        );
+        assert_eq!(device.update(0, "GPU-0", reading(40, 5), &CONFIG, NOW + 60), None);
+        assert!(!device.healthy_at(NOW + 629));
+        assert_eq!(describe(&device, NOW + 629), "recent 2 new uncorrected ECC errors");
+
+        assert_eq!(device.update(0, "GPU-0", reading(40, 5), &CONFIG, NOW + 630), Some(Change::Recovered));
+        assert_eq!(describe(&device, NOW + 630), "");
+    }
+
+    #[test]
+    fn hot_or_throttled_cards_are_benched_while_they_are() {
+        let mut device = DeviceHealth::default();
+        assert_eq!(device.update(1, "GPU-1", reading(70, 0), &CONFIG, NOW), None);
+        assert_eq!(
+            device.update(1, "GPU-1", reading(90, 0), &CONFIG, NOW + 30),
+            Some(Change::Unhealthy("too hot (90C)".to_string()))
+        );
+        let throttled = Ok(Reading {
+            temperature: Some(80),
+            ecc_errors: Some(0),
+            throttle_reasons: Some(ThrottleReasons::HW_SLOWDOWN | ThrottleReasons::SW_POWER_CAP),
+        });
+        assert_eq!(device.update(1, "GPU-1", throttled, &CONFIG, NOW + 60), None);
+        assert_eq!(device.problems, vec!["hardware slowdown"]);
+        assert_eq!(device.update(1, "GPU-1", reading(80, 0), &CONFIG, NOW + 90), Some(Change::Recovered));
+    }
+
+    #[test]
+    fn unreachable_cards_are_benched() {
+        let mut device = DeviceHealth::default();
+        let change = device.update(2, "GPU-2", Err(NvmlError::GpuLost), &CONFIG, NOW);
+        assert!(matches!(change, Some(Change::Unhealthy(why)) if why.starts_with("unreachable")));
+        assert_eq!(device.temperature, None);
+    }
+
+    #[test]
+    fn polling_goes_through_the_readings() {
+        let uuid = "GPU-polling-goes-through-the-readings";
+        assert!(is_healthy(uuid));
+        poll_device(&FakeReadings(Some(reading(99, 0)).into()), 3, uuid, &CONFIG);
+        assert!(!is_healthy(uuid));
+        poll_device(&FakeReadings(Some(reading(50, 0)).into()), 3, uuid, &CONFIG);
+        assert!(is_healthy(uuid));
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use std::{
+    io::{self, Cursor, Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+};
+
+// Just enough HTTP/1.1 for the metrics endpoint and the API, which only ever
+// talk to things on this machine. Every connection carries one request.
+
+// Biggest request head we'll take, before the body
+const MAX_HEAD: usize = 16 * 1024;
+
+// Listen on TCP, as long as it's only on this machine
+pub fn listen_loopback(address: &str) -> Result<TcpListener> {
+    let addresses: Vec<_> = address.to_socket_addrs()?.collect();
+    if addresses.is_empty() || addresses.iter().any(|a| !a.ip().is_loopback()) {
+        return Err(anyhow!("{} isn't a loopback address", address));
+    }
+    Ok(TcpListener::bind(&addresses[..])?)
+}
+
+// A connection, over TCP or a unix socket
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+pub struct Request {
+    pub method: String,
+    pub path: String, // Decoded, without the query
+    query: Vec<(String, String)>, // Decoded
+    headers: Vec<(String, String)>, // Names are lowercase
+    leftover: Vec<u8>, // The start of the body, if it came in with the head
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("content-length").and_then(|len| len.parse().ok())
+    }
+
+    // The body, which is however much Content-Length says. Has to be read
+    // before responding.
+    pub fn body<'a>(&'a self, stream: &'a mut dyn Stream) -> impl Read + 'a {
+        let len = self.content_length().unwrap_or(0);
+        // Clients that asked first won't send a body until we say so
+        if self.header("expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+            let _ = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+        let leftover = &self.leftover[..self.leftover.len().min(len as usize)];
+        Cursor::new(leftover).chain(stream.take(len - leftover.len() as u64))
+    }
+}
+
+pub fn read_request(stream: &mut dyn Stream) -> io::Result<Request> {
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request head is too big"));
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed mid-request")),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut words = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(&name.replace('+', " ")), decode(&value.replace('+', " ")))
+        })
+        .collect();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok(Request {
+        method,
+        path: decode(path),
+        query,
+        headers,
+        leftover: buf[head_len + 4..].to_vec(),
+    })
+}
+
+// Undo percent-encoding
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// Send a whole response. `headers` are any besides the content's type and
+// length.
+pub fn respond(stream: &mut dyn Stream, status: &str, content_type: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n", status, content_type, body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)
+}
+
+// Start a response whose length we don't know yet. The body is whatever's
+// written after this, up until the connection closes.
+pub fn respond_unsized(stream: &mut dyn Stream, status: &str, content_type: &str, headers: &[(&str, &str)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\n", status, content_type);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Hands out what it was given a chunk per read, like a socket would, and
+    // keeps whatever's written to it
+    #[derive(Default)]
+    struct FakeStream {
+        chunks: VecDeque<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl FakeStream {
+        fn new(chunks: &[&[u8]]) -> FakeStream {
+            FakeStream { chunks: chunks.iter().map(|c| c.to_vec()).collect(), ..Default::default() }
+        }
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(mut chunk) = self.chunks.pop_front() else {
+                return Ok(0);
+            };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks.push_front(chunk.split_off(n));
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_loopback_addresses_are_listened_on() {
+        assert!(listen_loopback("127.0.0.1:0").is_ok());
+        assert!(listen_loopback("localhost:0").is_ok());
+        for address in ["0.0.0.0:0", "[::]:0", "192.0.2.1:9464"] {
+            let e = listen_loopback(address).err().unwrap();
+            assert!(e.to_string().contains("isn't a loopback address"), "{}: {}", address, e);
+        }
+    }
+
+    #[test]
+    fn oversized_heads_are_refused() {
+        let mut stream = FakeStream::new(&[b"GET / HTTP/1.1\r\n", &[b'a'; MAX_HEAD + 1]]);
+        let e = read_request(&mut stream).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn paths_and_queries_are_decoded() {
+        let mut stream = FakeStream::new(&[b"POST /activities/whisper%2Dlarge/jobs?name=my+lecture%2Emp4&draft HTTP/1.1\r\nContent-Type: video/mp4\r\n\r\n"]);
+        let request = read_request(&mut stream).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/activities/whisper-large/jobs");
+        assert_eq!(request.query("name"), Some("my lecture.mp4"));
+        assert_eq!(request.query("draft"), Some(""));
+        assert_eq!(request.header("content-type"), Some("video/mp4"));
+    }
+
+    #[test]
+    fn bodies_pick_up_where_the_head_left_off() {
+        let mut stream = FakeStream::new(&[
+            b"POST /jobs HTTP/1.1\r\nContent-Length: 10\r\nExpect: 100-continue\r\n\r\nhello",
+            b"world and then some",
+        ]);
+        let request = read_request(&mut stream).unwrap();
+        assert_eq!(request.leftover, b"hello");
+        let mut body = String::new();
+        request.body(&mut stream).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "helloworld");
+        assert_eq!(stream.written, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn bodies_stop_at_their_length() {
+        let mut stream = FakeStream::new(&[b"POST /jobs HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef"]);
+        let request = read_request(&mut stream).unwrap();
+        let mut body = String::new();
+        request.body(&mut stream).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abc");
+        assert!(stream.written.is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+};
+use crate::runner::container::ImageBuild;
+use crate::runner::run_command;
+
+// What we last built for an activity
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageRecord {
+    pub image: String, // The tag we built, like srvrs-whisper
+    pub context_hash: String, // SHA-256 over the Dockerfile and build context
+    pub digest: String, // The image ID the engine gave us
+    pub built: i64, // Unix timestamp
+}
+
+// One YAML file per activity, written by `srvrs build`.
+pub struct ImageRegistry {
+    pub dir: String,
+}
+
+impl ImageRegistry {
+    pub fn get(&self, activity: &str) -> Option<ImageRecord> {
+        let contents = fs::read_to_string(format!("{}/{}.yaml", self.dir, activity)).ok()?;
+        match serde_yaml::from_str(&contents) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Ignoring the image record for {}: {}", activity, e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, activity: &str, record: &ImageRecord) -> Result<()> {
+        let path = format!("{}/{}.yaml", self.dir, activity);
+        let mut rf = fs::File::create(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        rf.write_all(serde_yaml::to_string(record)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+// Hash everything that goes into a build: the Dockerfile, then every file in
+// the context by its path, permissions and contents. Symlinks are hashed by
+// where they point rather than followed.
+pub fn hash_context(context: &Path, dockerfile: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(fs::read(dockerfile)?);
+    hash_dir(&mut hasher, context, context)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_dir(hasher: &mut Sha256, root: &Path, dir: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let meta = fs::symlink_metadata(&path)?;
+        let relative = path.strip_prefix(root)?;
+        // Separate the fields so that names and contents can't run together
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(meta.permissions().mode().to_le_bytes());
+        if meta.is_dir() {
+            hash_dir(hasher, root, &path)?;
+        } else if meta.file_type().is_symlink() {
+            hasher.update(fs::read_link(&path)?.to_string_lossy().as_bytes());
+        } else if meta.is_file() {
+            hasher.update(meta.len().to_le_bytes());
+            hasher.update(fs::read(&path)?);
+        }
+        hasher.update([0]);
+    }
+    Ok(())
+}
+
+// The ID of an image, or None if the engine doesn't have it.
+pub fn image_digest(engine: &str, image: &str) -> Result<Option<String>> {
+    let output = Command::new(engine)
+        .args(["image", "inspect", "--format", "{{.Id}}", image])
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_string()))
+}
+
+// Build an activity's image if its context changed since the last build, or
+// if the engine lost it. Returns whether it built anything.
+pub fn build_image(
+    activity: &str,
+    engine: &str,
+    image: &str,
+    build: &ImageBuild,
+    base_dir: &str,
+    registry: &ImageRegistry,
+    force: bool,
+) -> Result<bool> {
+    let in_base_dir = |path: &str| Path::new(base_dir).join(path);
+    let context = in_base_dir(&build.context);
+    let dockerfile = match &build.dockerfile {
+        Some(dockerfile) => context.join(dockerfile),
+        None => context.join("Dockerfile"),
+    };
+    let context_hash = hash_context(&context, &dockerfile)?;
+
+    let unchanged = registry
+        .get(activity)
+        .is_some_and(|r| r.image == image && r.context_hash == context_hash);
+    if !force && unchanged && image_digest(engine, image)?.is_some() {
+        info!("{} is up to date", image);
+        return Ok(false);
+    }
+
+    info!("Building {} for {} from {}", image, activity, context.display());
+    let mut cmd = Command::new(engine);
+    cmd.arg("build")
+        .arg("--tag").arg(image)
+        .arg("--file").arg(&dockerfile)
+        .arg(&context);
+    let report = run_command(cmd, &mut |line| println!("{}", line))?;
+    if !report.status.success() {
+        return Err(anyhow!("Building {} failed: {}", image, report.status));
+    }
+
+    let digest = image_digest(engine, image)?
+        .ok_or_else(|| anyhow!("{} built, but {} can't find it", image, engine))?;
+    info!("Built {} as {}", image, digest);
+    registry.save(activity, &ImageRecord {
+        image: image.to_string(),
+        context_hash,
+        digest,
+        built: chrono::offset::Local::now().timestamp(),
+    })?;
+    Ok(true)
+}
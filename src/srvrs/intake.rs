@@ -0,0 +1,297 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::{
+    fs,
+    io::{self, Read},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use users::get_user_by_uid;
+use crate::SRVRS_UID;
+use crate::job::{Job, JobRegistry, Outcome, SubmittedFile};
+
+// A file somebody dropped off, as we found it. Users control what's in the
+// watch directories, so we look at uploads without following symlinks, and
+// make sure the file we end up running on is the one we looked at.
+pub struct Upload {
+    pub owner: String, // Who it belongs to, and so who the results go to
+    pub job_id: Option<String>, // The ID its job was promised, if it was submitted through the API
+    dev: u64,
+    ino: u64,
+}
+
+pub fn check_upload(path: &Path) -> Result<Upload> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => anyhow!("{} is a symlink", path.display()),
+            _ => e.into(),
+        })?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(anyhow!("{} is not a regular file", path.display()));
+    }
+    // A hard link could be to somebody else's file, and we'd hand its
+    // contents back to whoever made the link.
+    if meta.nlink() > 1 {
+        return Err(anyhow!("{} is hard linked", path.display()));
+    }
+    // Files of our own came in through the API, on somebody else's behalf
+    if meta.uid() == *SRVRS_UID {
+        let mut submissions = SUBMISSIONS.lock().unwrap();
+        return match submissions.iter().position(|s| s.dev == meta.dev() && s.ino == meta.ino()) {
+            Some(i) => {
+                let submission = submissions.remove(i);
+                Ok(Upload {
+                    owner: submission.owner,
+                    job_id: Some(submission.job_id),
+                    dev: meta.dev(),
+                    ino: meta.ino(),
+                })
+            }
+            None => Err(anyhow!("{} belongs to srvrs, but wasn't submitted", path.display())),
+        };
+    }
+    let owner = match get_user_by_uid(meta.uid()) {
+        Some(user) => user.name().to_string_lossy().to_string(),
+        None => return Err(anyhow!("Could not find an owner for {}", path.display())),
+    };
+    Ok(Upload {
+        owner,
+        job_id: None,
+        dev: meta.dev(),
+        ino: meta.ino(),
+    })
+}
+
+// A file handed to us through the API rather than dropped in a watch
+// directory. We write those ourselves, so they belong to srvrs, and who
+// they're really from is kept here until their activity gets to them. Each
+// one is recorded as a queued job too, so it outlives a restart.
+#[derive(Debug, Clone)]
+pub struct Submission {
+    pub job_id: String, // The ID its job will get
+    pub owner: String,
+    pub input: String, // Name of the file
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+lazy_static! {
+    static ref SUBMISSIONS: Mutex<Vec<Submission>> = Mutex::new(vec![]);
+}
+
+// Drop a file off in an activity's watch directory for somebody, just as if
+// they'd put it there themselves. `body` has to have `len` bytes in it.
+// Returns the ID its job will get.
+pub fn submit(
+    activity: &str,
+    watch_dir: &str,
+    registry: &JobRegistry,
+    name: &str,
+    owner: &str,
+    body: &mut dyn Read,
+    len: u64,
+) -> Result<String> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\0']) {
+        return Err(anyhow!("{:?} is not a name srvrs can use", name));
+    }
+    let path = Path::new(watch_dir).join(name);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => anyhow!("There's already a {} waiting for {}", name, activity),
+            _ => e.into(),
+        })?;
+    let meta = file.metadata()?;
+    let mut job = Job::new(activity, owner, name);
+    job.outcome = Outcome::Queued;
+    job.submitted = Some(SubmittedFile { dev: meta.dev(), ino: meta.ino() });
+    if let Err(e) = registry.save(&job) {
+        fs::remove_file(&path)?;
+        return Err(e);
+    }
+    SUBMISSIONS.lock().unwrap().push(Submission {
+        job_id: job.id.clone(),
+        owner: owner.to_string(),
+        input: name.to_string(),
+        path: path.clone(),
+        dev: meta.dev(),
+        ino: meta.ino(),
+    });
+    // The activity picks it up once it's closed, so anything that goes wrong
+    // has to be cleaned up before then
+    match io::copy(&mut body.take(len), &mut file) {
+        Ok(written) if written == len => Ok(job.id),
+        result => {
+            withdraw(&job.id)?;
+            registry.remove(&job.id)?;
+            match result {
+                Ok(written) => Err(anyhow!("Only got {} of {} bytes", written, len)),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+// Take back the submissions an activity had waiting when srvrs last stopped.
+// The ones whose file is still there are queued again, and returned, since
+// nothing will tell the activity they're there. The rest are recorded as
+// failed.
+pub fn readmit(activity: &str, watch_dir: &str, registry: &JobRegistry) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for mut job in registry.all()? {
+        if job.activity != activity || job.outcome != Outcome::Queued {
+            continue;
+        }
+        let path = Path::new(watch_dir).join(&job.input);
+        match (&job.submitted, fs::symlink_metadata(&path)) {
+            (Some(file), Ok(meta)) if meta.dev() == file.dev && meta.ino() == file.ino => {
+                SUBMISSIONS.lock().unwrap().push(Submission {
+                    job_id: job.id.clone(),
+                    owner: job.owner.clone(),
+                    input: job.input.clone(),
+                    path: path.clone(),
+                    dev: file.dev,
+                    ino: file.ino,
+                });
+                paths.push(path);
+            }
+            _ => {
+                job.finish(&Err(anyhow!("{} was gone when srvrs restarted", job.input)));
+                registry.save(&job)?;
+            }
+        }
+    }
+    Ok(paths)
+}
+
+// Take back a submission before its activity gets to it, returning it if it
+// was still waiting
+pub fn withdraw(job_id: &str) -> Result<Option<Submission>> {
+    let mut submissions = SUBMISSIONS.lock().unwrap();
+    let Some(i) = submissions.iter().position(|s| s.job_id == job_id) else {
+        return Ok(None);
+    };
+    let submission = submissions.remove(i);
+    // Only remove it if it's still the file we wrote
+    match fs::symlink_metadata(&submission.path) {
+        Ok(meta) if meta.dev() == submission.dev && meta.ino() == submission.ino => {
+            fs::remove_file(&submission.path)?;
+        }
+        _ => {}
+    }
+    Ok(Some(submission))
+}
+
+impl Upload {
+    // Make sure what's at path now is still the file we checked, and hasn't
+    // been linked to since.
+    pub fn verify(&self, path: &Path) -> Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        if meta.dev() != self.dev || meta.ino() != self.ino || meta.nlink() > 1 {
+            return Err(anyhow!("{} changed after it was checked", path.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, os::unix::{ffi::OsStrExt, fs::symlink}};
+
+    fn upload_of(path: &Path) -> Upload {
+        let meta = fs::symlink_metadata(path).unwrap();
+        Upload { owner: "someone".to_string(), job_id: None, dev: meta.dev(), ino: meta.ino() }
+    }
+
+    #[test]
+    fn symlinks_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upload = tmp.path().join("upload.mp4");
+        symlink("/etc/shadow", &upload).unwrap();
+        let e = check_upload(&upload).err().unwrap();
+        assert!(e.to_string().contains("is a symlink"), "{}", e);
+    }
+
+    #[test]
+    fn hard_links_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let original = tmp.path().join("somebody-elses");
+        fs::write(&original, "secret").unwrap();
+        let upload = tmp.path().join("upload.mp4");
+        fs::hard_link(&original, &upload).unwrap();
+        let e = check_upload(&upload).err().unwrap();
+        assert!(e.to_string().contains("is hard linked"), "{}", e);
+    }
+
+    #[test]
+    fn fifos_are_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upload = tmp.path().join("upload.mp4");
+        let name = CString::new(upload.as_os_str().as_bytes()).unwrap();
+        // SAFETY: name is a valid C string for the whole call.
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o600) }, 0);
+        let e = check_upload(&upload).err().unwrap();
+        assert!(e.to_string().contains("is not a regular file"), "{}", e);
+    }
+
+    #[test]
+    fn verify_catches_a_swapped_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upload = tmp.path().join("upload.mp4");
+        fs::write(&upload, "mine").unwrap();
+        let checked = upload_of(&upload);
+        assert!(checked.verify(&upload).is_ok());
+
+        // Swapped for another file after it was checked
+        let other = tmp.path().join("other");
+        fs::write(&other, "not mine").unwrap();
+        fs::rename(&other, &upload).unwrap();
+        assert!(checked.verify(&upload).is_err());
+    }
+
+    #[test]
+    fn verify_catches_a_late_hard_link() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upload = tmp.path().join("upload.mp4");
+        fs::write(&upload, "mine").unwrap();
+        let checked = upload_of(&upload);
+        fs::hard_link(&upload, tmp.path().join("link")).unwrap();
+        assert!(checked.verify(&upload).is_err());
+    }
+
+    #[test]
+    fn submissions_still_there_after_a_restart_are_readmitted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let watch_dir = tmp.path().join("whisper");
+        let jobs_dir = tmp.path().join("jobs");
+        fs::create_dir(&watch_dir).unwrap();
+        fs::create_dir(&jobs_dir).unwrap();
+        let upload = watch_dir.join("lecture.mp4");
+        fs::write(&upload, "video").unwrap();
+        let meta = fs::symlink_metadata(&upload).unwrap();
+        let mut job = Job::new("whisper", "someone", "lecture.mp4");
+        job.outcome = Outcome::Queued;
+        job.submitted = Some(SubmittedFile { dev: meta.dev(), ino: meta.ino() });
+        fs::write(jobs_dir.join(format!("{}.yaml", job.id)), serde_yaml::to_string(&job).unwrap()).unwrap();
+
+        let registry = JobRegistry { dir: jobs_dir.to_string_lossy().to_string() };
+        let paths = readmit("stable-diffusion", watch_dir.to_str().unwrap(), &registry).unwrap();
+        assert!(paths.is_empty());
+        let paths = readmit("whisper", watch_dir.to_str().unwrap(), &registry).unwrap();
+        assert_eq!(paths, vec![upload.clone()]);
+        // It's waiting again, so it can still be taken back
+        let submission = withdraw(&job.id).unwrap().unwrap();
+        assert_eq!(submission.owner, "someone");
+        assert!(!upload.exists());
+    }
+}
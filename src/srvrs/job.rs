@@ -0,0 +1,196 @@
+use anyhow::Result;
+use chrono::TimeZone;
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::{chown, PermissionsExt},
+    sync::Mutex,
+};
+use crate::{SRVRS_UID, MEMBERS_GID};
+use crate::runner::Cancelled;
+use crate::runner::limits::OutOfMemory;
+use crate::usage::Usage;
+
+lazy_static! {
+    // The millisecond the last job ID was made for
+    static ref LAST_ID_MILLIS: Mutex<i64> = Mutex::new(0);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Queued, // Submitted through the API, and waiting for its activity
+    Running,
+    Succeeded,
+    Failed,
+    OutOfMemory, // Killed for going over the activity's memory limit
+    Cancelled, // Stopped because somebody asked
+}
+
+impl Outcome {
+    // How it's written for machines, like in metrics and the API
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Queued => "queued",
+            Outcome::Running => "running",
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::OutOfMemory => "out_of_memory",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+// A job is one run of an activity on one file somebody dropped off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String, // Unique name of this job, also its file name in the registry
+    pub activity: String, // The activity that ran it
+    pub owner: String, // The user who uploaded the input
+    pub input: String, // Name of the file that was uploaded
+    pub started: i64, // Unix timestamp of when we picked the file up
+    pub finished: Option<i64>, // Unix timestamp of when we were done with it
+    pub outcome: Outcome,
+    pub error: Option<String>, // What went wrong, if anything
+    #[serde(default)]
+    pub usage: Usage, // What the job cost us
+    #[serde(default)]
+    pub delivered_to: Option<String>, // Where the results ended up, filled in by the distributor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted: Option<SubmittedFile>, // The file we wrote for it, while it's queued
+}
+
+// Which file a submission is, so we know it's still ours after a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SubmittedFile {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl Job {
+    pub fn new(activity: &str, owner: &str, input: &str) -> Job {
+        let now = chrono::offset::Local::now();
+        Job {
+            id: Job::new_id(activity),
+            activity: activity.to_string(),
+            owner: owner.to_string(),
+            input: input.to_string(),
+            started: now.timestamp(),
+            finished: None,
+            outcome: Outcome::Running,
+            error: None,
+            usage: Usage::default(),
+            delivered_to: None,
+            submitted: None,
+        }
+    }
+
+    // IDs are the activity and when the job came in, to the millisecond. Two
+    // jobs coming in the same millisecond, from the API and a watch directory
+    // say, get the next free one, so no two IDs are ever the same.
+    pub fn new_id(activity: &str) -> String {
+        let mut last = LAST_ID_MILLIS.lock().unwrap();
+        *last = chrono::offset::Local::now().timestamp_millis().max(*last + 1);
+        let when = chrono::offset::Local.timestamp_millis_opt(*last).unwrap();
+        format!("{}-{}", activity, when.format("%Y%m%dT%H%M%S%3f"))
+    }
+
+    pub fn finish(&mut self, result: &Result<()>) {
+        self.finished = Some(chrono::offset::Local::now().timestamp());
+        self.submitted = None;
+        match result {
+            Ok(()) => self.outcome = Outcome::Succeeded,
+            Err(e) => {
+                self.outcome = if e.downcast_ref::<OutOfMemory>().is_some() {
+                    Outcome::OutOfMemory
+                } else if e.downcast_ref::<Cancelled>().is_some() {
+                    Outcome::Cancelled
+                } else {
+                    Outcome::Failed
+                };
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+// The job registry is a directory with one YAML file per job. It's world
+// readable so that `srvrs usage` works for anybody.
+pub struct JobRegistry {
+    pub dir: String,
+}
+
+impl JobRegistry {
+    pub fn save(&self, job: &Job) -> Result<()> {
+        let path = format!("{}/{}.yaml", self.dir, job.id);
+        let mut jf = fs::File::create(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        chown(&path, Some(*SRVRS_UID), Some(*MEMBERS_GID))?;
+        jf.write_all(serde_yaml::to_string(job)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        fs::remove_file(format!("{}/{}.yaml", self.dir, id))?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Job> {
+        let contents = fs::read_to_string(format!("{}/{}.yaml", self.dir, id))?;
+        Ok(serde_yaml::from_str(&contents)?)
+    }
+
+    // Read every job we know about, skipping anything that can't be read or
+    // doesn't parse.
+    pub fn all(&self) -> Result<Vec<Job>> {
+        let mut jobs = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            match serde_yaml::from_str(&contents) {
+                Ok(job) => jobs.push(job),
+                Err(e) => warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+        jobs.sort_by_key(|j: &Job| j.started);
+        Ok(jobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_made_together_are_unique() {
+        let ids: Vec<String> = (0..1000).map(|_| Job::new_id("whisper")).collect();
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+        // And they still sort in the order the jobs came in
+        assert_eq!(unique, ids);
+    }
+
+    #[test]
+    fn unreadable_records_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let job = Job::new("whisper", "someone", "lecture.mp4");
+        fs::write(tmp.path().join(format!("{}.yaml", job.id)), serde_yaml::to_string(&job).unwrap()).unwrap();
+        fs::write(tmp.path().join("binary.yaml"), [0xff, 0xfe, 0x00]).unwrap();
+        fs::create_dir(tmp.path().join("not-a-job")).unwrap();
+        fs::write(tmp.path().join("garbage.yaml"), "[").unwrap();
+        let registry = JobRegistry { dir: tmp.path().to_string_lossy().to_string() };
+        let jobs = registry.all().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, job.id);
+    }
+}
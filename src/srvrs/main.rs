@@ -5,13 +5,30 @@ use std::{io::Read, fs, os::unix::fs::{PermissionsExt, chown}};
 use serde_yaml;
 use tokio;
 use log::{error, info, LevelFilter};
-use simple_logger::SimpleLogger;
 use users::{get_user_by_name, get_group_by_name};
 use lazy_static::lazy_static;
 use anyhow::Error;
+use std::{collections::HashMap, sync::Arc};
 
 pub mod activity;
+pub mod api;
+pub mod delivery;
 pub mod gpu;
+pub mod health;
+pub mod http;
+pub mod image;
+pub mod intake;
+pub mod job;
+#[path = "../common/logging.rs"]
+pub mod logging;
+pub mod metrics;
+#[path = "../common/nofollow.rs"]
+pub mod nofollow;
+#[path = "../common/prometheus.rs"]
+pub mod prometheus;
+pub mod runner;
+pub mod topology;
+pub mod usage;
 
 lazy_static! {
     static ref MEMBERS_GID: u32 = match get_group_by_name("member") {
@@ -35,6 +52,25 @@ lazy_static! {
 struct SubCommands {
     #[command(subcommand)]
     subcommand: Action,
+
+    /// Log level, overriding the config file
+    #[arg(long, global = true)]
+    log_level: Option<LevelFilter>,
+
+    /// Where to log, overriding the config file
+    #[arg(long, global = true)]
+    log_to: Option<logging::Backend>,
+}
+
+impl Action {
+    fn config_file(&self) -> Option<&str> {
+        match self {
+            Action::Setup(args) | Action::Watch(args) => Some(&args.config_file),
+            Action::Usage(args) => Some(&args.config_file),
+            Action::Build(args) => Some(&args.config_file),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -44,6 +80,8 @@ enum Action {
     Status,
     Services,
     Queue,
+    Usage(UsageArgs),
+    Build(BuildArgs),
 }
 
 #[derive(Args, Debug)]
@@ -53,11 +91,71 @@ struct WatchArgs {
     config_file: String,
 }
 
+#[derive(Args, Debug)]
+struct UsageArgs {
+    /// Config file
+    #[arg(short, long, default_value = "/etc/srvrs.yaml")]
+    config_file: String,
+
+    /// Only count jobs from this user
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Only count jobs started on or after this date (YYYY-MM-DD)
+    #[arg(short, long, value_parser = parse_date)]
+    since: Option<i64>,
+
+    /// Print comma separated values instead of a table
+    #[arg(long)]
+    csv: bool,
+}
+
+#[derive(Args, Debug)]
+struct BuildArgs {
+    /// Config file
+    #[arg(short, long, default_value = "/etc/srvrs.yaml")]
+    config_file: String,
+
+    /// Rebuild even if nothing changed
+    #[arg(short, long)]
+    force: bool,
+
+    /// Only build these activities
+    activities: Vec<String>,
+}
+
+// Turn a date into the unix timestamp of local midnight on that day
+fn parse_date(date: &str) -> Result<i64, String> {
+    let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| e.to_string())?;
+    day.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(chrono::offset::Local).earliest())
+        .map(|midnight| midnight.timestamp())
+        .ok_or_else(|| format!("{} has no local midnight", date))
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     let args = SubCommands::parse();
-    SimpleLogger::new().init().unwrap();
-    log::set_max_level(LevelFilter::Debug); // TODO: Configure this somehow?
+    // Logging is set up from the config file, if this command has one and it
+    // reads. If it doesn't, the command itself will say why.
+    let sc = args.subcommand.config_file()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|config| serde_yaml::from_str::<activity::SrvrsConfig>(&config).ok());
+    let (logging_config, activity_logs) = match &sc {
+        Some(sc) => (
+            sc.logging.clone(),
+            sc.activities.iter()
+                .filter_map(|(name, ac)| ac.log_file.clone().map(|file| (name.clone(), file)))
+                .collect(),
+        ),
+        None => (logging::LoggingConfig::default(), HashMap::new()),
+    };
+    let logging_config = logging_config.with_overrides(args.log_level, args.log_to);
+    if let Err(e) = logging::init(&logging_config, LevelFilter::Debug, &activity_logs) {
+        eprintln!("Could not set up logging: {:#}", e);
+        std::process::exit(1);
+    }
             
     match args.subcommand {
         Action::Setup(watch_args) => {
@@ -72,17 +170,19 @@ async fn main() {
             let queue_dir = format!("{}/queue", sc.base_dir);
             let work_dir = format!("{}/work", sc.base_dir);
             let distributor_dir = format!("{}/distributor", sc.base_dir);
+            let jobs_dir = format!("{}/jobs", sc.base_dir);
+            let images_dir = format!("{}/images", sc.base_dir);
 
             // Create base directories for srvrs
-            for dir in vec![&scripts_dir, &work_dir, &distributor_dir] {
+            for dir in vec![&scripts_dir, &work_dir, &distributor_dir, &images_dir] {
                 info!("Creating directory: {}", &dir);
                 fs::create_dir_all(&dir).unwrap();
                 fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
                 chown(dir, Some(*SRVRS_UID), Some(*SRVRS_GID)).unwrap();
             }
 
-            // Create status and queue directories
-            for dir in vec![&status_dir, &queue_dir] {
+            // Create status, queue and job registry directories
+            for dir in vec![&status_dir, &queue_dir, &jobs_dir] {
                 info!("Creating directory: {}", &dir);
                 fs::create_dir_all(&dir).unwrap();
                 fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
@@ -112,22 +212,59 @@ async fn main() {
             let queue_dir = format!("{}/queue", sc.base_dir);
             let work_dir = format!("{}/work", sc.base_dir);
             let distributor_dir = format!("{}/distributor", sc.base_dir);
+            let jobs_dir = format!("{}/jobs", sc.base_dir);
+
+            // Keep unhealthy GPUs out of rotation
+            health::monitor_health(sc.gpu_health.clone(), format!("{}/gpus", status_dir));
+
+            if let Some(mc) = &sc.metrics {
+                metrics::serve(mc.clone());
+            }
+
             // spawn tasks that run in parallel
             let mut items = vec![];
 
             for (name, ac) in &sc.activities {
-                items.push(activity::Activity {
+                let runner = match ac.runner.build(&scripts_dir) {
+                    Ok(runner) => runner,
+                    Err(e) => {
+                        error!("Not starting {}: {}", name, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = runner.check() {
+                    error!("Not starting {}: {}", name, e);
+                    continue;
+                }
+                items.push(Arc::new(activity::Activity {
                     name: name.clone(),
-                    script: format!("{}/{}", scripts_dir, ac.script),
+                    runner,
                     wants: ac.wants.clone(),
-                    gpus: ac.gpus,
+                    gpus: gpu::GpuRequest {
+                        count: ac.gpus,
+                        memory_mib: ac.gpu_memory_mib,
+                        max_jobs_per_gpu: sc.max_jobs_per_gpu,
+                        require_p2p: ac.require_p2p,
+                        cdi_naming: sc.cdi_device_names,
+                    },
                     progress_regex: ac.progress_regex.clone(),
+                    destination: ac.destination.clone().or_else(|| sc.destination.clone()),
+                    retention_days: ac.retention_days.or(sc.retention_days),
+                    delivery_format: ac.delivery_format.clone().or_else(|| sc.delivery_format.clone()).unwrap_or_default(),
+                    user_delivery_formats: sc.user_delivery_formats.clone(),
+                    sink: ac.sink.clone().or_else(|| sc.sink.clone()),
                     watch_dir: format!("{}/{}", sc.base_dir, name),
                     status_path: format!("{}/{}", status_dir, name),
                     queue_path: format!("{}/{}", queue_dir, name),
                     work_dir: work_dir.clone(),
-                    distributor_dir: distributor_dir.clone()
-                });
+                    distributor_dir: distributor_dir.clone(),
+                    jobs_dir: jobs_dir.clone(),
+                    progress: Default::default(),
+                }));
+            }
+
+            if let Some(ac) = &sc.api {
+                api::serve(ac.clone(), items.clone(), jobs_dir.clone());
             }
 
             let tasks: Vec<_> = items
@@ -159,9 +296,72 @@ async fn main() {
         Action::Queue => {
             print_for_users("/var/srvrs/queue");
         }
+        Action::Usage(usage_args) => {
+            let config = fs::read_to_string(usage_args.config_file).unwrap();
+            let sc: activity::SrvrsConfig = serde_yaml::from_str(&config).unwrap();
+            let registry = job::JobRegistry { dir: format!("{}/jobs", sc.base_dir) };
+            let jobs = match registry.all() {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    error!("Could not read job registry: {}", e);
+                    return;
+                }
+            };
+            let rows = usage::summarize(&jobs, usage_args.user.as_deref(), usage_args.since);
+            let shared_gpus = sc.max_jobs_per_gpu > 1;
+            if usage_args.csv {
+                usage::print_csv(&rows);
+                // Kept out of the way of whatever reads the CSV
+                if shared_gpus {
+                    eprintln!("{}", usage::SHARED_GPU_NOTE);
+                }
+            } else {
+                usage::print_table(&rows, shared_gpus);
+            }
+        }
+        Action::Build(build_args) => {
+            let config = fs::read_to_string(build_args.config_file).unwrap();
+            let sc: activity::SrvrsConfig = serde_yaml::from_str(&config).unwrap();
+            if let Err(e) = build_images(&sc, &build_args.activities, build_args.force) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
     }
 }
 
+// Build every container image the config says how to build, skipping the
+// ones whose build context hasn't changed.
+fn build_images(sc: &activity::SrvrsConfig, only: &[String], force: bool) -> Result<(), Error> {
+    let registry = image::ImageRegistry { dir: format!("{}/images", sc.base_dir) };
+    let mut failed = vec![];
+    for (name, ac) in &sc.activities {
+        if !only.is_empty() && !only.contains(name) {
+            continue;
+        }
+        let engine = match ac.runner.runner {
+            runner::RunnerKind::Podman => "podman",
+            runner::RunnerKind::Docker => "docker",
+            _ => continue,
+        };
+        let (container, build) = match &ac.runner.container {
+            Some(container) => match &container.build {
+                Some(build) => (container, build),
+                None => continue,
+            },
+            None => continue,
+        };
+        if let Err(e) = image::build_image(name, engine, &container.image, build, &sc.base_dir, &registry, force) {
+            error!("Could not build {}: {}", name, e);
+            failed.push(name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("Failed to build {}", failed.join(", ")));
+    }
+    Ok(())
+}
+
 fn print_for_users(dir_path: &str) -> Result<(), Error> {
     let dir = fs::read_dir(dir_path)?;
     for file in dir {
@@ -0,0 +1,202 @@
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::TcpStream,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+use crate::gpu::{jobs_per_device, NVML};
+use crate::health::is_healthy;
+use crate::http;
+use crate::job::Outcome;
+use crate::prometheus::{self, header, label, sample, Histogram, SECONDS_BUCKETS};
+
+// Where the distributor keeps its own metrics, which we pass along. Has to
+// agree with the distributor.
+const DISTRIBUTOR_METRICS: &str = "/run/srvrs-distributor/metrics.prom";
+
+// Every outcome a job can have, so they all show up from the start
+const OUTCOMES: [Outcome; 4] = [Outcome::Succeeded, Outcome::Failed, Outcome::OutOfMemory, Outcome::Cancelled];
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String, // Address to serve /metrics on, like 127.0.0.1:9464
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:9464".to_string()
+}
+
+// What each activity has been up to since srvrs started
+#[derive(Default)]
+struct Metrics {
+    watch_dirs: BTreeMap<String, String>, // Where each activity's queue is
+    running: BTreeMap<String, u64>, // Jobs each activity is on right now
+    jobs: BTreeMap<(String, &'static str), u64>, // Finished jobs, by activity and outcome
+    durations: BTreeMap<String, Histogram>, // How long each activity's scripts ran
+    gpu_waits: BTreeMap<String, Histogram>, // How long each activity waited for GPUs
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+// Start counting for an activity, so it's reported even before it's done
+// anything
+pub fn register(activity: &str, watch_dir: &str) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.watch_dirs.insert(activity.to_string(), watch_dir.to_string());
+    metrics.running.insert(activity.to_string(), 0);
+    for outcome in &OUTCOMES {
+        metrics.jobs.insert((activity.to_string(), outcome.label()), 0);
+    }
+    metrics.durations.insert(activity.to_string(), Histogram::new(SECONDS_BUCKETS));
+    metrics.gpu_waits.insert(activity.to_string(), Histogram::new(SECONDS_BUCKETS));
+}
+
+pub fn job_started(activity: &str) {
+    *METRICS.lock().unwrap().running.entry(activity.to_string()).or_default() += 1;
+}
+
+pub fn job_finished(activity: &str, outcome: &Outcome) {
+    let mut metrics = METRICS.lock().unwrap();
+    if let Some(running) = metrics.running.get_mut(activity) {
+        *running = running.saturating_sub(1);
+    }
+    *metrics.jobs.entry((activity.to_string(), outcome.label())).or_default() += 1;
+}
+
+// Jobs cancelled while they were still waiting were never running
+pub fn job_withdrawn(activity: &str) {
+    *METRICS.lock().unwrap().jobs.entry((activity.to_string(), Outcome::Cancelled.label())).or_default() += 1;
+}
+
+pub fn observe_gpu_wait(activity: &str, secs: f64) {
+    METRICS.lock().unwrap().gpu_waits
+        .entry(activity.to_string())
+        .or_insert_with(|| Histogram::new(SECONDS_BUCKETS))
+        .observe(secs);
+}
+
+pub fn observe_duration(activity: &str, secs: f64) {
+    METRICS.lock().unwrap().durations
+        .entry(activity.to_string())
+        .or_insert_with(|| Histogram::new(SECONDS_BUCKETS))
+        .observe(secs);
+}
+
+// Serve /metrics in the background. Scrapes are rare and quick, so they're
+// answered one at a time. Only on this machine, though.
+pub fn serve(config: MetricsConfig) {
+    let listener = match http::listen_loopback(&config.listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Cannot serve metrics on {}: {:#}", config.listen, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", config.listen);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => answer(stream).unwrap_or_else(|e| warn!("Could not answer a scrape: {}", e)),
+                Err(e) => warn!("Could not accept a scrape: {}", e),
+            }
+        }
+    });
+}
+
+fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let request = http::read_request(&mut stream)?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            http::respond(&mut stream, "200 OK", "text/plain; version=0.0.4", &[], render().as_bytes())
+        }
+        ("GET", _) => http::respond(&mut stream, "404 Not Found", "text/plain", &[], b"Try /metrics\n"),
+        _ => http::respond(&mut stream, "405 Method Not Allowed", "text/plain", &[], b"Only GET is supported\n"),
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    {
+        let metrics = METRICS.lock().unwrap();
+        header(&mut out, "srvrs_queue_length", "gauge", "Uploads waiting in each activity's directory");
+        for (activity, dir) in &metrics.watch_dirs {
+            if let Ok(entries) = fs::read_dir(dir) {
+                sample(&mut out, "srvrs_queue_length", &label("activity", activity), entries.count() as f64);
+            }
+        }
+        header(&mut out, "srvrs_jobs_running", "gauge", "Jobs each activity is working on");
+        for (activity, running) in &metrics.running {
+            sample(&mut out, "srvrs_jobs_running", &label("activity", activity), *running as f64);
+        }
+        header(&mut out, "srvrs_jobs_total", "counter", "Jobs finished, by how they went");
+        for ((activity, outcome), count) in &metrics.jobs {
+            let labels = prometheus::join(&label("activity", activity), &label("outcome", outcome));
+            sample(&mut out, "srvrs_jobs_total", &labels, *count as f64);
+        }
+        header(&mut out, "srvrs_job_duration_seconds", "histogram", "How long jobs' scripts ran");
+        for (activity, histogram) in &metrics.durations {
+            histogram.render(&mut out, "srvrs_job_duration_seconds", &label("activity", activity));
+        }
+        header(&mut out, "srvrs_gpu_wait_seconds", "histogram", "How long jobs waited for GPUs");
+        for (activity, histogram) in &metrics.gpu_waits {
+            histogram.render(&mut out, "srvrs_gpu_wait_seconds", &label("activity", activity));
+        }
+    }
+    render_devices(&mut out);
+    // The distributor runs as root on its own, so it leaves its numbers
+    // where we can read them
+    if let Ok(distributor) = fs::read_to_string(DISTRIBUTOR_METRICS) {
+        out.push_str(&distributor);
+    }
+    out
+}
+
+fn render_devices(out: &mut String) {
+    let Ok(count) = (*NVML).device_count() else {
+        return;
+    };
+    let jobs = jobs_per_device();
+    let mut jobs_lines = String::new();
+    let mut utilization_lines = String::new();
+    let mut used_lines = String::new();
+    let mut total_lines = String::new();
+    let mut healthy_lines = String::new();
+    for index in 0..count {
+        let Ok(device) = (*NVML).device_by_index(index) else {
+            continue;
+        };
+        let Ok(uuid) = device.uuid() else {
+            continue;
+        };
+        let labels = prometheus::join(&label("gpu", &index.to_string()), &label("uuid", &uuid));
+        sample(&mut jobs_lines, "srvrs_gpu_jobs", &labels, *jobs.get(&uuid).unwrap_or(&0) as f64);
+        if let Ok(rates) = device.utilization_rates() {
+            sample(&mut utilization_lines, "srvrs_gpu_utilization_ratio", &labels, rates.gpu as f64 / 100.0);
+        }
+        if let Ok(memory) = device.memory_info() {
+            sample(&mut used_lines, "srvrs_gpu_memory_used_bytes", &labels, memory.used as f64);
+            sample(&mut total_lines, "srvrs_gpu_memory_total_bytes", &labels, memory.total as f64);
+        }
+        sample(&mut healthy_lines, "srvrs_gpu_healthy", &labels, is_healthy(&uuid) as u8 as f64);
+    }
+    header(out, "srvrs_gpu_jobs", "gauge", "srvrs jobs each GPU is allocated to");
+    out.push_str(&jobs_lines);
+    header(out, "srvrs_gpu_utilization_ratio", "gauge", "How busy each GPU is, from 0 to 1");
+    out.push_str(&utilization_lines);
+    header(out, "srvrs_gpu_memory_used_bytes", "gauge", "Memory in use on each GPU, by anything");
+    out.push_str(&used_lines);
+    header(out, "srvrs_gpu_memory_total_bytes", "gauge", "Memory on each GPU");
+    out.push_str(&total_lines);
+    header(out, "srvrs_gpu_healthy", "gauge", "Whether each GPU is taking new work");
+    out.push_str(&healthy_lines);
+}
@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use log::warn;
+use crate::image::image_digest;
+use super::limits::ResourceLimits;
+use super::{run_job_command, RunContext, RunReport, Runner};
+
+// Where the job's work directory shows up inside the container
+const CONTAINER_WORKDIR: &str = "/workdir";
+
+// How often to check how much CPU a container has used
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How to run an activity in a container, without writing a script to do it.
+// Env values and args can use {input} (the input's path in the container),
+// {input_name}, {workdir} and {job_id}.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContainerConfig {
+    pub image: String, // The image to run, like srvrs-whisper
+    #[serde(default)]
+    pub env: BTreeMap<String, String>, // Environment for the container
+    #[serde(default)]
+    pub mounts: Vec<String>, // Extra volumes, as host:container[:options]
+    pub entrypoint: Option<String>, // Replaces the image's entrypoint
+    #[serde(default)]
+    pub args: Vec<String>, // Passed to the entrypoint
+    pub build: Option<ImageBuild>, // How `srvrs build` makes the image
+}
+
+// Where an image's Dockerfile and build context live
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImageBuild {
+    pub context: String, // Build context, relative to the base dir
+    pub dockerfile: Option<String>, // Relative to the context, Dockerfile if left out
+}
+
+// Runs an activity's container with podman or docker
+pub struct ContainerRunner {
+    pub engine: String, // podman or docker
+    pub container: ContainerConfig,
+    pub limits: ResourceLimits, // Passed to the engine as flags
+}
+
+impl fmt::Display for ContainerRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} run {}", self.engine, self.container.image)
+    }
+}
+
+impl Runner for ContainerRunner {
+    fn check(&self) -> Result<()> {
+        let image = &self.container.image;
+        match image_digest(&self.engine, image)? {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("{} doesn't have {}. Build it with `srvrs build`.", self.engine, image)),
+        }
+    }
+
+    fn run(&self, ctx: &RunContext, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+        let name = container_name(ctx.job_id);
+        let sampler = CpuSampler::start(self.engine.clone(), name.clone());
+        let result = run_job_command(self.command(ctx), ctx.job_id, on_line);
+        let container_cpu_secs = sampler.finish();
+
+        // The container is kept around after it exits so we can ask whether
+        // it ran out of memory, so clean it up whatever happened.
+        let oom_killed = self.oom_killed(&name);
+        if let Err(e) = Command::new(&self.engine).args(["rm", "--force", &name]).output() {
+            warn!("Could not remove container {}: {}", name, e);
+        }
+
+        let mut report = result?;
+        // What we reaped was only the engine's client
+        match container_cpu_secs {
+            Some(cpu_secs) => report.cpu_secs = cpu_secs,
+            None => warn!("Could not tell how much CPU {} used", name),
+        }
+        if oom_killed {
+            report.out_of_memory = Some(self.limits.out_of_memory());
+        }
+        Ok(report)
+    }
+    // The engine runs the container on its own, so it's the one to stop it
+    fn cancel(&self, job_id: &str) -> Result<()> {
+        let output = Command::new(&self.engine).args(["stop", &container_name(job_id)]).output()?;
+        if !output.status.success() {
+            return Err(anyhow!("{} stop failed: {}", self.engine, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+fn container_name(job_id: &str) -> String {
+    format!("srvrs-{}", job_id)
+}
+
+impl ContainerRunner {
+    // Build the `podman run` for one job. The directory holding the input is
+    // mounted at /workdir, and every GPU the job was given is passed through
+    // by its CDI name.
+    fn command(&self, ctx: &RunContext) -> Command {
+        let job_id = ctx.job_id;
+        let container = &self.container;
+        let input_path = Path::new(ctx.input);
+        let input_name = input_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let host_workdir = input_path
+            .parent()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        let container_input = format!("{}/{}", CONTAINER_WORKDIR, input_name);
+        let fill = |template: &str| {
+            template
+                .replace("{input}", &container_input)
+                .replace("{input_name}", &input_name)
+                .replace("{workdir}", CONTAINER_WORKDIR)
+                .replace("{job_id}", job_id)
+        };
+
+        let mut cmd = Command::new(&self.engine);
+        cmd.arg("run").arg(format!("--name={}", container_name(job_id)));
+        cmd.args(self.limits.container_flags());
+        for gpu in ctx.gpus {
+            cmd.arg("--device").arg(&gpu.cdi_name);
+        }
+        cmd.arg("-v").arg(format!("{}:{}:Z", host_workdir, CONTAINER_WORKDIR));
+        for mount in &container.mounts {
+            cmd.arg("-v").arg(mount);
+        }
+        for (key, value) in &container.env {
+            cmd.arg("-e").arg(format!("{}={}", key, fill(value)));
+        }
+        if let Some(entrypoint) = &container.entrypoint {
+            cmd.arg(format!("--entrypoint={}", entrypoint));
+        }
+        cmd.arg(&container.image);
+        for arg in &container.args {
+            cmd.arg(fill(arg));
+        }
+        cmd
+    }
+
+    fn oom_killed(&self, name: &str) -> bool {
+        match Command::new(&self.engine)
+            .args(["inspect", "--format", "{{.State.OOMKilled}}", name])
+            .output()
+        {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).trim() == "true",
+            Err(e) => {
+                warn!("Could not inspect container {}: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+// Polls the CPU time a container has used, from its cgroup, in the
+// background. The engine runs the container rather than us, so its time
+// isn't in what we reap, and its cgroup goes away as soon as it exits, so
+// the last moment before it does goes uncounted. Only works on cgroup v2.
+struct CpuSampler {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Option<f64>>,
+}
+
+impl CpuSampler {
+    fn start(engine: String, name: String) -> CpuSampler {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut cpu_stat = None;
+            let mut latest = None;
+            while !stopped.load(Ordering::Relaxed) {
+                // It takes a moment for the container to start
+                if cpu_stat.is_none() {
+                    cpu_stat = cgroup_cpu_stat(&engine, &name);
+                }
+                if let Some(cpu_secs) = cpu_stat.as_ref().and_then(|path| read_cpu_secs(path)) {
+                    latest = Some(cpu_secs);
+                }
+                thread::park_timeout(CPU_SAMPLE_INTERVAL);
+            }
+            latest
+        });
+        CpuSampler { stop, handle }
+    }
+
+    // Stop sampling and return the last CPU time seen, in seconds
+    fn finish(self) -> Option<f64> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.thread().unpark();
+        self.handle.join().unwrap_or(None)
+    }
+}
+
+// Where a running container's cgroup keeps its CPU time
+fn cgroup_cpu_stat(engine: &str, name: &str) -> Option<PathBuf> {
+    let output = Command::new(engine)
+        .args(["inspect", "--format", "{{.State.Pid}}", name])
+        .output()
+        .ok()?;
+    let pid: u32 = String::from_utf8_lossy(&output.stdout).trim().parse().ok()?;
+    if pid == 0 {
+        return None;
+    }
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let cgroup = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new("/sys/fs/cgroup").join(cgroup.trim_start_matches('/')).join("cpu.stat"))
+}
+
+fn read_cpu_secs(cpu_stat: &Path) -> Option<f64> {
+    fs::read_to_string(cpu_stat).ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usec| usec.trim().parse::<u64>().ok())
+        .map(|usec| usec as f64 / 1e6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::GpuDevice;
+
+    fn gpu(index: u32, uuid: &str) -> GpuDevice {
+        GpuDevice { index, uuid: uuid.to_string(), cdi_name: format!("nvidia.com/gpu={}", uuid), minor: Some(index) }
+    }
+
+    #[test]
+    fn commands_pass_the_job_through() {
+        let runner = ContainerRunner {
+            engine: "podman".to_string(),
+            container: ContainerConfig {
+                image: "srvrs-whisper".to_string(),
+                env: BTreeMap::from([
+                    ("INPUT".to_string(), "{input}".to_string()),
+                    ("JOB".to_string(), "{job_id}".to_string()),
+                ]),
+                mounts: vec!["/srv/models:/models:ro".to_string()],
+                entrypoint: Some("whisper".to_string()),
+                args: vec!["{input}".to_string(), "--output_dir={workdir}".to_string(), "--name={input_name}".to_string()],
+                build: None,
+            },
+            limits: ResourceLimits { memory: Some("16G".to_string()), ..Default::default() },
+        };
+        let gpus = [gpu(0, "GPU-aaa"), gpu(1, "GPU-bbb")];
+        let ctx = RunContext { job_id: "job-1", input: "/var/srvrs/work/job-1/lecture.mp4", gpus: &gpus };
+
+        let cmd = runner.command(&ctx);
+        assert_eq!(cmd.get_program(), "podman");
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy().to_string()).collect();
+        assert_eq!(args, [
+            "run",
+            "--name=srvrs-job-1",
+            "--memory=16G",
+            "--memory-swap=16G",
+            "--device",
+            "nvidia.com/gpu=GPU-aaa",
+            "--device",
+            "nvidia.com/gpu=GPU-bbb",
+            "-v",
+            "/var/srvrs/work/job-1:/workdir:Z",
+            "-v",
+            "/srv/models:/models:ro",
+            "-e",
+            "INPUT=/workdir/lecture.mp4",
+            "-e",
+            "JOB=job-1",
+            "--entrypoint=whisper",
+            "srvrs-whisper",
+            "/workdir/lecture.mp4",
+            "--output_dir=/workdir",
+            "--name=lecture.mp4",
+        ]);
+    }
+}
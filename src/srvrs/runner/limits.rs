@@ -0,0 +1,154 @@
+use log::warn;
+use serde::Deserialize;
+use std::{fmt, process::Command};
+use super::wrap_command;
+
+// What a single job is allowed to use. Anything left out is unlimited.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ResourceLimits {
+    pub cpus: Option<f64>, // How many CPUs worth of time, like 2.5
+    pub memory: Option<String>, // Like 16G. Swap is off for limited jobs so they hit this instead.
+    pub pids: Option<u64>, // How many processes and threads
+    pub io_weight: Option<u64>, // Share of disk time, 1 to 10000 where 100 is normal
+    pub io_device: Option<String>, // The disk the bandwidth limits apply to, like /dev/nvme0n1
+    pub io_read_bandwidth: Option<String>, // Like 200M, per second
+    pub io_write_bandwidth: Option<String>, // Like 200M, per second
+}
+
+// A job got killed for using more memory than it was allowed
+#[derive(Debug)]
+pub struct OutOfMemory {
+    pub limit: String,
+}
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Killed for using more than its {} of memory", self.limit)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+impl ResourceLimits {
+    // The limits as systemd unit properties
+    pub fn properties(&self) -> Vec<String> {
+        let mut properties = vec![];
+        if let Some(cpus) = self.cpus {
+            properties.push(format!("CPUQuota={}%", (cpus * 100.0).round()));
+        }
+        if let Some(memory) = &self.memory {
+            properties.push(format!("MemoryMax={}", memory));
+            properties.push("MemorySwapMax=0".to_string());
+        }
+        if let Some(pids) = self.pids {
+            properties.push(format!("TasksMax={}", pids));
+        }
+        if let Some(io_weight) = self.io_weight {
+            properties.push(format!("IOWeight={}", io_weight));
+        }
+        if let Some(device) = &self.io_device {
+            if let Some(read) = &self.io_read_bandwidth {
+                properties.push(format!("IOReadBandwidthMax={} {}", device, read));
+            }
+            if let Some(write) = &self.io_write_bandwidth {
+                properties.push(format!("IOWriteBandwidthMax={} {}", device, write));
+            }
+        }
+        properties
+    }
+
+    // The limits as `podman run`/`docker run` flags. Containers live in
+    // cgroups the engine makes for them, so they have to be limited there.
+    pub fn container_flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(cpus) = self.cpus {
+            flags.push(format!("--cpus={}", cpus));
+        }
+        if let Some(memory) = &self.memory {
+            flags.push(format!("--memory={}", memory));
+            flags.push(format!("--memory-swap={}", memory));
+        }
+        if let Some(pids) = self.pids {
+            flags.push(format!("--pids-limit={}", pids));
+        }
+        if let Some(io_weight) = self.io_weight {
+            // The engines only take 10 to 1000 here
+            flags.push(format!("--blkio-weight={}", io_weight.clamp(10, 1000)));
+        }
+        if let Some(device) = &self.io_device {
+            if let Some(read) = &self.io_read_bandwidth {
+                flags.push(format!("--device-read-bps={}:{}", device, read));
+            }
+            if let Some(write) = &self.io_write_bandwidth {
+                flags.push(format!("--device-write-bps={}:{}", device, write));
+            }
+        }
+        flags
+    }
+
+    // Wrap a command so that it runs in its own transient scope with these
+    // limits. The scope execs the command in place, so we still get its
+    // output and its rusage.
+    pub fn scope(&self, cmd: &Command, unit: &str) -> Command {
+        let mut scoped = Command::new("systemd-run");
+        scoped.arg("--user")
+            .arg("--scope")
+            .arg("--quiet")
+            .arg(format!("--unit={}", unit));
+        for property in self.properties() {
+            scoped.arg(format!("--property={}", property));
+        }
+        scoped.arg("--");
+        wrap_command(scoped, cmd)
+    }
+
+    pub fn out_of_memory(&self) -> OutOfMemory {
+        OutOfMemory {
+            limit: self.memory.clone().unwrap_or_default(),
+        }
+    }
+}
+
+// Name the transient unit for a job. systemd is picky about unit names.
+pub fn unit_name(job_id: &str, suffix: &str) -> String {
+    let name: String = job_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.:".contains(c) { c } else { '_' })
+        .collect();
+    format!("srvrs-{}.{}", name, suffix)
+}
+
+// How a transient unit finished, according to systemd
+#[derive(Debug, Default)]
+pub struct UnitOutcome {
+    pub oom_killed: bool,
+    pub cpu_secs: Option<f64>,
+}
+
+// Ask systemd how a finished unit went, then clear it out. Units that failed
+// stick around until somebody does, which is what lets us ask.
+pub fn finished_unit(unit: &str) -> UnitOutcome {
+    let mut outcome = UnitOutcome::default();
+    match Command::new("systemctl")
+        .args(["--user", "show", unit, "--property=Result,CPUUsageNSec"])
+        .output()
+    {
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                match line.split_once('=') {
+                    Some(("Result", result)) => outcome.oom_killed = result == "oom-kill",
+                    Some(("CPUUsageNSec", nsec)) => {
+                        outcome.cpu_secs = nsec.parse::<u64>().ok().map(|n| n as f64 / 1e9);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => warn!("Could not ask systemd about {}: {}", unit, e),
+    }
+    // Nothing to reset if it succeeded, so don't care how this goes.
+    let _ = Command::new("systemctl")
+        .args(["--user", "reset-failed", unit])
+        .output();
+    outcome
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    io::{self, BufRead, BufReader},
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+};
+use crate::gpu::GpuDevice;
+use crate::usage::wait_for_exit;
+
+pub mod container;
+pub mod limits;
+pub mod replay;
+pub mod sandbox;
+pub mod script;
+pub mod systemd;
+
+use container::{ContainerConfig, ContainerRunner};
+use limits::{OutOfMemory, ResourceLimits};
+use replay::ReplayRunner;
+use sandbox::SandboxConfig;
+use script::ScriptRunner;
+use systemd::SystemdRunner;
+
+lazy_static! {
+    // Jobs somebody asked us to stop
+    static ref CANCELLED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+
+    // The process group of whatever's running for each job
+    static ref COMMANDS: Mutex<HashMap<String, i32>> = Mutex::new(HashMap::new());
+}
+
+// A job was stopped because somebody asked
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// Mark a job as cancelled, so it stops at the next chance it gets
+pub fn mark_cancelled(job_id: &str) {
+    CANCELLED.lock().unwrap().insert(job_id.to_string());
+}
+
+pub fn is_cancelled(job_id: &str) -> bool {
+    CANCELLED.lock().unwrap().contains(job_id)
+}
+
+// Forget about a job once it's done, cancelled or not
+pub fn forget(job_id: &str) {
+    CANCELLED.lock().unwrap().remove(job_id);
+}
+
+// Everything a runner gets to know about the job it's running
+pub struct RunContext<'a> {
+    pub job_id: &'a str,
+    pub input: &'a str, // Path to the input, inside the job's work directory
+    pub gpus: &'a [GpuDevice], // The GPUs the job was given
+}
+
+// How a run went
+pub struct RunReport {
+    pub status: ExitStatus,
+    pub cpu_secs: f64, // CPU time of whatever we ran and everything it waited on
+    pub out_of_memory: Option<OutOfMemory>, // Set if it was killed for going over its memory limit
+}
+
+// Something that can do an activity's work. Output has to come back line by
+// line through `on_line` so that the activity can pick progress out of it.
+pub trait Runner: Display + Send + Sync {
+    fn run(&self, ctx: &RunContext, on_line: &mut dyn FnMut(&str)) -> Result<RunReport>;
+
+    // Make sure whatever the runner needs is there before any work comes in.
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Stop a job's run, if it's running. `run` returns once it has stopped.
+    fn cancel(&self, job_id: &str) -> Result<()> {
+        kill_command(job_id)
+    }
+}
+
+// Run a command, streaming its stdout, and reap it.
+pub fn run_command(mut cmd: Command, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+    let child = cmd.stdout(Stdio::piped()).spawn()?;
+    stream_and_reap(child, on_line)
+}
+
+// Run a command for a job, like run_command, but in a process group of its
+// own so it can be cancelled along with everything it started.
+pub fn run_job_command(mut cmd: Command, job_id: &str, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+    let child = cmd.stdout(Stdio::piped()).process_group(0).spawn()?;
+    COMMANDS.lock().unwrap().insert(job_id.to_string(), child.id() as i32);
+    let result = stream_and_reap(child, on_line);
+    COMMANDS.lock().unwrap().remove(job_id);
+    result
+}
+
+// Run a command for a job, like run_job_command, passing its stderr along to
+// ours as it comes and keeping the last `keep` lines of it, for commands that
+// sum up how things went there.
+pub fn run_job_command_keeping_stderr(
+    mut cmd: Command,
+    job_id: &str,
+    on_line: &mut dyn FnMut(&str),
+    keep: usize,
+) -> Result<(RunReport, Vec<String>)> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).process_group(0).spawn()?;
+    let stderr = child.stderr.take().unwrap();
+    COMMANDS.lock().unwrap().insert(job_id.to_string(), child.id() as i32);
+    let (result, tail) = thread::scope(|scope| {
+        let tail = scope.spawn(move || {
+            let mut tail = VecDeque::new();
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("{}", line);
+                tail.push_back(line);
+                if tail.len() > keep {
+                    tail.pop_front();
+                }
+            }
+            tail
+        });
+        let result = stream_and_reap(child, on_line);
+        (result, tail.join().unwrap_or_default())
+    });
+    COMMANDS.lock().unwrap().remove(job_id);
+    Ok((result?, tail.into()))
+}
+
+fn stream_and_reap(mut child: Child, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+    let child_stdout = child.stdout.take().unwrap();
+    for line in BufReader::new(child_stdout).lines() {
+        match line {
+            Ok(l) => on_line(&l),
+            _ => warn!("Could not read command ouput."),
+        }
+    }
+
+    let (status, cpu_secs) = wait_for_exit(&child)?;
+    Ok(RunReport {
+        status,
+        cpu_secs,
+        out_of_memory: None,
+    })
+}
+
+// Stop whatever run_job_command is running for a job
+pub fn kill_command(job_id: &str) -> Result<()> {
+    let Some(pgid) = COMMANDS.lock().unwrap().get(job_id).copied() else {
+        return Ok(());
+    };
+    // SAFETY: kill has no memory safety requirements.
+    if unsafe { libc::killpg(pgid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Put a command on the end of a wrapper like systemd-run or bwrap, which
+// will exec it, and have the wrapper pass along its environment and working
+// directory.
+pub fn wrap_command(mut wrapper: Command, cmd: &Command) -> Command {
+    wrapper.arg(cmd.get_program()).args(cmd.get_args());
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => wrapper.env(key, value),
+            None => wrapper.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        wrapper.current_dir(dir);
+    }
+    wrapper
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunnerKind {
+    #[default]
+    Script, // Run a script on this machine
+    Podman, // Run a container with podman
+    Docker, // Run a container with docker
+    Systemd, // Run a script in its own transient systemd unit
+    Replay, // Pretend to run, playing back output captured earlier
+}
+
+// The part of an activity's config that says how its work gets done
+#[derive(Deserialize, Debug, Clone)]
+pub struct RunnerConfig {
+    #[serde(default)]
+    pub runner: RunnerKind,
+    pub script: Option<String>, // Script in the scripts dir, for script and systemd
+    pub container: Option<ContainerConfig>, // Container to run, for podman and docker
+    pub replay: Option<String>, // File of canned output in the scripts dir, for replay
+    pub limits: Option<ResourceLimits>, // What each job may use. Replays ignore this.
+    pub sandbox: Option<SandboxConfig>, // Fence in the script, for script and systemd
+}
+
+impl RunnerConfig {
+    // Work out what will actually run, making sure we were told enough to
+    // run it.
+    pub fn build(&self, scripts_dir: &str) -> Result<Box<dyn Runner>> {
+        let in_scripts_dir = |file: &Option<String>, what: &str| match file {
+            Some(file) => Ok(format!("{}/{}", scripts_dir, file)),
+            None => Err(anyhow!("The {:?} runner needs a {}", self.runner, what)),
+        };
+        let container = || match &self.container {
+            Some(container) => Ok(container.clone()),
+            None => Err(anyhow!("The {:?} runner needs a container", self.runner)),
+        };
+        // Containers are already fenced in, and replays don't run anything.
+        let bare_metal = matches!(self.runner, RunnerKind::Script | RunnerKind::Systemd);
+        if self.sandbox.is_some() && !bare_metal {
+            return Err(anyhow!("The {:?} runner can't be sandboxed", self.runner));
+        }
+        Ok(match self.runner {
+            RunnerKind::Script => Box::new(ScriptRunner {
+                script: in_scripts_dir(&self.script, "script")?,
+                limits: self.limits.clone(),
+                sandbox: self.sandbox.clone(),
+            }),
+            RunnerKind::Podman => Box::new(ContainerRunner {
+                engine: "podman".to_string(),
+                container: container()?,
+                limits: self.limits.clone().unwrap_or_default(),
+            }),
+            RunnerKind::Docker => Box::new(ContainerRunner {
+                engine: "docker".to_string(),
+                container: container()?,
+                limits: self.limits.clone().unwrap_or_default(),
+            }),
+            RunnerKind::Systemd => Box::new(SystemdRunner {
+                script: in_scripts_dir(&self.script, "script")?,
+                limits: self.limits.clone().unwrap_or_default(),
+                sandbox: self.sandbox.clone(),
+            }),
+            RunnerKind::Replay => Box::new(ReplayRunner {
+                output: in_scripts_dir(&self.replay, "replay file")?,
+            }),
+        })
+    }
+}
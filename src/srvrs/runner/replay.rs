@@ -0,0 +1,77 @@
+use anyhow::Result;
+use std::{fmt, fs, os::unix::process::ExitStatusExt, process::ExitStatus, thread, time::Duration};
+use super::{is_cancelled, RunContext, RunReport, Runner};
+
+// How long to wait between lines, so that status updates are visible
+const LINE_DELAY: Duration = Duration::from_millis(50);
+
+// Doesn't run anything. Plays back output captured from a real run, which is
+// handy for trying out progress regexes and the rest of the pipeline on a
+// machine without the real thing.
+pub struct ReplayRunner {
+    pub output: String, // Path to the captured output
+}
+
+impl fmt::Display for ReplayRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "replay {}", self.output)
+    }
+}
+
+impl Runner for ReplayRunner {
+    fn run(&self, ctx: &RunContext, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+        for line in fs::read_to_string(&self.output)?.lines() {
+            // There's nothing to kill, so stop playing instead
+            if is_cancelled(ctx.job_id) {
+                break;
+            }
+            on_line(line);
+            thread::sleep(LINE_DELAY);
+        }
+        Ok(RunReport {
+            status: ExitStatus::from_raw(0),
+            cpu_secs: 0.0,
+            out_of_memory: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{forget, mark_cancelled};
+
+    fn play(job_id: &str, cancel_after: Option<usize>) -> (RunReport, Vec<String>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("output.txt");
+        fs::write(&output, "sd2 iteration progress: 1 of 3\nsd2 iteration progress: 2 of 3\nsd2 iteration progress: 3 of 3\n").unwrap();
+        let runner: Box<dyn Runner> = Box::new(ReplayRunner { output: output.to_string_lossy().to_string() });
+        let ctx = RunContext { job_id, input: "prompt.txt", gpus: &[] };
+        let mut lines = vec![];
+        let report = runner.run(&ctx, &mut |l| {
+            lines.push(l.to_string());
+            if cancel_after == Some(lines.len()) {
+                mark_cancelled(job_id);
+            }
+        }).unwrap();
+        forget(job_id);
+        (report, lines)
+    }
+
+    #[test]
+    fn plays_every_line_back() {
+        let (report, lines) = play("replay-plays-every-line", None);
+        assert!(report.status.success());
+        assert_eq!(lines, vec![
+            "sd2 iteration progress: 1 of 3",
+            "sd2 iteration progress: 2 of 3",
+            "sd2 iteration progress: 3 of 3",
+        ]);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let (_, lines) = play("replay-stops-when-cancelled", Some(2));
+        assert_eq!(lines.len(), 2);
+    }
+}
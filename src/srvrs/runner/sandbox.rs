@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use std::{path::Path, process::Command};
+use super::{wrap_command, RunContext};
+
+// What the script needs from the base system to run at all. These go in read
+// only, and any that don't exist on this machine are skipped.
+const SYSTEM_PATHS: [&str; 6] = ["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"];
+
+// The NVIDIA device nodes every CUDA program needs on top of the GPUs
+// themselves
+const NVIDIA_CONTROL_DEVICES: [&str; 4] = [
+    "/dev/nvidiactl",
+    "/dev/nvidia-uvm",
+    "/dev/nvidia-uvm-tools",
+    "/dev/nvidia-modeset",
+];
+
+// Runs a script under bubblewrap, in its own namespaces. It sees the base
+// system and whatever was declared here read only, its script, its job's work
+// directory read-write, and only the GPUs it was given.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SandboxConfig {
+    #[serde(default)]
+    pub read_only: Vec<String>, // Extra paths it can read, like model weights
+    #[serde(default)]
+    pub network: bool, // Let it reach the network. Off unless asked for.
+}
+
+impl SandboxConfig {
+    // Wrap a command so it runs in the sandbox. The command's program is
+    // taken to be the script, and is the only thing outside of the work
+    // directory it can see that wasn't asked for.
+    pub fn wrap(&self, cmd: &Command, ctx: &RunContext) -> Command {
+        let mut bwrap = Command::new("bwrap");
+        bwrap.arg("--unshare-all")
+            .arg("--die-with-parent")
+            .arg("--new-session"); // Keep it off our terminal
+        if self.network {
+            bwrap.arg("--share-net");
+        }
+        for path in SYSTEM_PATHS {
+            bwrap.arg("--ro-bind-try").arg(path).arg(path);
+        }
+        bwrap.arg("--proc").arg("/proc")
+            .arg("--dev").arg("/dev")
+            .arg("--tmpfs").arg("/tmp");
+
+        for path in NVIDIA_CONTROL_DEVICES {
+            bwrap.arg("--dev-bind-try").arg(path).arg(path);
+        }
+        for gpu in ctx.gpus {
+            if let Some(minor) = gpu.minor {
+                let node = format!("/dev/nvidia{}", minor);
+                bwrap.arg("--dev-bind-try").arg(&node).arg(&node);
+            }
+        }
+
+        for path in &self.read_only {
+            bwrap.arg("--ro-bind").arg(path).arg(path);
+        }
+        let script = cmd.get_program();
+        bwrap.arg("--ro-bind").arg(script).arg(script);
+        if let Some(work_dir) = Path::new(ctx.input).parent() {
+            bwrap.arg("--bind").arg(work_dir).arg(work_dir)
+                .arg("--chdir").arg(work_dir);
+        }
+        bwrap.arg("--");
+        wrap_command(bwrap, cmd)
+    }
+}
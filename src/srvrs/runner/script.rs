@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::{fmt, process::Command};
+use crate::gpu::{device_env, device_list};
+use super::limits::{finished_unit, unit_name, ResourceLimits};
+use super::sandbox::SandboxConfig;
+use super::{run_job_command, RunContext, RunReport, Runner};
+
+// Runs a script on this machine, with the input and the GPUs it can use as
+// arguments. If it has limits, it runs in its own transient systemd scope.
+pub struct ScriptRunner {
+    pub script: String, // Path to script this will run
+    pub limits: Option<ResourceLimits>,
+    pub sandbox: Option<SandboxConfig>,
+}
+
+impl fmt::Display for ScriptRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.script)
+    }
+}
+
+impl Runner for ScriptRunner {
+    fn run(&self, ctx: &RunContext, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+        let mut cmd = Command::new(&self.script);
+        cmd.arg(ctx.input)
+            .arg(device_list(ctx.gpus))
+            .envs(device_env(ctx.gpus));
+        if let Some(sandbox) = &self.sandbox {
+            cmd = sandbox.wrap(&cmd, ctx);
+        }
+
+        let limits = match &self.limits {
+            Some(limits) => limits,
+            None => return run_job_command(cmd, ctx.job_id, on_line),
+        };
+        let unit = unit_name(ctx.job_id, "scope");
+        let mut report = run_job_command(limits.scope(&cmd, &unit), ctx.job_id, on_line)?;
+        if finished_unit(&unit).oom_killed {
+            report.out_of_memory = Some(limits.out_of_memory());
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{fmt, process::Command};
+use crate::gpu::{device_env, device_list};
+use super::limits::{finished_unit, unit_name, ResourceLimits};
+use super::sandbox::SandboxConfig;
+use super::{run_job_command_keeping_stderr, RunContext, RunReport, Runner};
+
+// How many lines of systemd-run's stderr to look through for its summary
+const SUMMARY_LINES: usize = 10;
+
+// Runs a script as a transient unit in the srvrs user's systemd instance, so
+// it gets its own cgroup and shows up in `systemctl --user` while it runs.
+// The srvrs user needs lingering enabled for its systemd instance to exist.
+pub struct SystemdRunner {
+    pub script: String, // Path to script this will run
+    pub limits: ResourceLimits, // Set as properties on the unit
+    pub sandbox: Option<SandboxConfig>,
+}
+
+impl fmt::Display for SystemdRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "systemd-run {}", self.script)
+    }
+}
+
+impl Runner for SystemdRunner {
+    fn run(&self, ctx: &RunContext, on_line: &mut dyn FnMut(&str)) -> Result<RunReport> {
+        let unit = unit_name(ctx.job_id, "service");
+        let mut cmd = Command::new("systemd-run");
+        cmd.arg("--user")
+            .arg("--wait") // Stick around until the unit is done...
+            .arg("--pipe") // ...and hand us its output, then sum up how it went
+            .arg(format!("--unit={}", unit));
+        for property in self.limits.properties() {
+            cmd.arg(format!("--property={}", property));
+        }
+        // The unit doesn't inherit our environment, so pass along the GPUs.
+        for (key, value) in device_env(ctx.gpus) {
+            cmd.arg(format!("--setenv={}={}", key, value));
+        }
+        let mut script = Command::new(&self.script);
+        script.arg(ctx.input).arg(device_list(ctx.gpus));
+        if let Some(sandbox) = &self.sandbox {
+            script = sandbox.wrap(&script, ctx);
+        }
+        cmd.arg("--").arg(script.get_program()).args(script.get_args());
+        let (mut report, summary) = run_job_command_keeping_stderr(cmd, ctx.job_id, on_line, SUMMARY_LINES)?;
+
+        // The script ran under systemd rather than under us, so systemd is
+        // the one who knows how much CPU it used. Units that succeed are gone
+        // by the time systemd-run returns, so that's only in its summary.
+        let outcome = finished_unit(&unit);
+        let summed_up = summary.iter()
+            .find_map(|line| line.strip_prefix("CPU time consumed: "))
+            .and_then(parse_timespan);
+        match summed_up.or(outcome.cpu_secs) {
+            Some(cpu_secs) => report.cpu_secs = cpu_secs,
+            None => warn!("Could not tell how much CPU {} used", unit),
+        }
+        if outcome.oom_killed {
+            report.out_of_memory = Some(self.limits.out_of_memory());
+        }
+        Ok(report)
+    }
+    // The script runs under systemd rather than under us, so systemd is the
+    // one to stop it
+    fn cancel(&self, job_id: &str) -> Result<()> {
+        let unit = unit_name(job_id, "service");
+        let output = Command::new("systemctl").args(["--user", "stop", &unit]).output()?;
+        if !output.status.success() {
+            return Err(anyhow!("Could not stop {}: {}", unit, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+// Read a time span the way systemd writes them, like 1min 2.345s or 38ms, in
+// seconds
+fn parse_timespan(span: &str) -> Option<f64> {
+    let mut secs = 0.0;
+    for part in span.split_whitespace() {
+        let split = part.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let (number, unit) = part.split_at(split);
+        let scale = match unit {
+            "y" => 31_557_600.0,
+            "month" => 2_629_800.0,
+            "w" => 604_800.0,
+            "d" => 86_400.0,
+            "h" => 3_600.0,
+            "min" => 60.0,
+            "s" => 1.0,
+            "ms" => 1e-3,
+            "us" | "μs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return None,
+        };
+        secs += number.parse::<f64>().ok()? * scale;
+    }
+    Some(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_systemd_timespans() {
+        assert_eq!(parse_timespan("38ms"), Some(0.038));
+        assert_eq!(parse_timespan("2.500s"), Some(2.5));
+        assert_eq!(parse_timespan("1h 2min 3.250s"), Some(3723.25));
+        assert_eq!(parse_timespan("12us"), Some(12e-6));
+        assert_eq!(parse_timespan("soon"), None);
+        assert_eq!(parse_timespan("[not set]"), None);
+    }
+}
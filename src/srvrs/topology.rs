@@ -0,0 +1,176 @@
+use itertools::Itertools;
+use nvml_wrapper::{enum_wrappers::device::TopologyLevel, error::NvmlError};
+use crate::gpu::{GpuDevice, NVML};
+
+// NVML_NVLINK_MAX_LINKS
+const NVLINK_MAX_LINKS: u32 = 18;
+
+// How two GPUs are connected, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Link {
+    NvLink, // Directly bridged
+    Board, // Two GPUs on one card, like a K80
+    Switch, // Behind the same PCIe switch
+    Switches, // Behind a tree of PCIe switches, but not the host bridge
+    HostBridge, // Through the CPU's PCIe host bridge
+    Node, // Through the interconnect between host bridges on one CPU
+    System, // Across CPU sockets
+}
+
+impl Link {
+    // Peer-to-peer works over NVLink and anywhere short of crossing PCIe
+    // host bridges.
+    pub fn p2p_capable(self) -> bool {
+        self <= Link::HostBridge
+    }
+}
+
+// Where we learn how devices are wired together. NVML in real life, anything
+// that can answer the question in tests.
+pub trait Topology {
+    fn link(&self, a: &GpuDevice, b: &GpuDevice) -> Result<Link, NvmlError>;
+}
+
+pub struct NvmlTopology;
+
+impl Topology for NvmlTopology {
+    fn link(&self, a: &GpuDevice, b: &GpuDevice) -> Result<Link, NvmlError> {
+        let device_a = (*NVML).device_by_uuid(a.uuid.as_str())?;
+        let device_b = (*NVML).device_by_uuid(b.uuid.as_str())?;
+
+        // Look for an active NVLink from a that lands on b
+        let b_bus_id = device_b.pci_info()?.bus_id;
+        for link_number in 0..NVLINK_MAX_LINKS {
+            let link = device_a.link_wrapper_for(link_number);
+            if !link.is_active().unwrap_or(false) {
+                continue;
+            }
+            if link.remote_pci_info().is_ok_and(|remote| remote.bus_id == b_bus_id) {
+                return Ok(Link::NvLink);
+            }
+        }
+
+        Ok(match device_a.topology_common_ancestor(device_b)? {
+            TopologyLevel::Internal => Link::Board,
+            TopologyLevel::Single => Link::Switch,
+            TopologyLevel::Multiple => Link::Switches,
+            TopologyLevel::HostBridge => Link::HostBridge,
+            TopologyLevel::Node => Link::Node,
+            TopologyLevel::System => Link::System,
+        })
+    }
+}
+
+// Out of the free devices, pick the <requesting> that are best connected to
+// each other: the set whose worst link is best, then the one with the best
+// links overall, then the lowest indices. Returns None if there aren't enough
+// devices, or if P2P is required and no set of them can do it.
+pub fn pick_devices(
+    topology: &dyn Topology,
+    free_devices: &[GpuDevice],
+    requesting: usize,
+    require_p2p: bool,
+) -> Result<Option<Vec<GpuDevice>>, NvmlError> {
+    if free_devices.len() < requesting {
+        return Ok(None);
+    }
+    // Nothing to connect
+    if requesting <= 1 {
+        return Ok(Some(free_devices[0..requesting].to_vec()));
+    }
+
+    let mut best: Option<(Vec<Link>, Vec<GpuDevice>)> = None;
+    for set in free_devices.iter().cloned().combinations(requesting) {
+        let mut links = vec![];
+        for pair in set.iter().combinations(2) {
+            links.push(topology.link(pair[0], pair[1])?);
+        }
+        // Worst link first, so that comparing two sets compares their worst
+        // links, then their next worst, and so on.
+        links.sort_by(|a, b| b.cmp(a));
+        if require_p2p && !links[0].p2p_capable() {
+            continue;
+        }
+        // Ties go to the set we saw first, which has the lowest indices.
+        if best.as_ref().is_none_or(|(best_links, _)| links < *best_links) {
+            best = Some((links, set));
+        }
+    }
+    Ok(best.map(|(_, set)| set))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Devices are wired together however the test says, and across sockets
+    // otherwise
+    struct FakeTopology(HashMap<(u32, u32), Link>);
+
+    impl Topology for FakeTopology {
+        fn link(&self, a: &GpuDevice, b: &GpuDevice) -> Result<Link, NvmlError> {
+            let pair = (a.index.min(b.index), a.index.max(b.index));
+            Ok(self.0.get(&pair).copied().unwrap_or(Link::System))
+        }
+    }
+
+    fn devices(count: u32) -> Vec<GpuDevice> {
+        (0..count)
+            .map(|index| GpuDevice {
+                index,
+                uuid: format!("GPU-{}", index),
+                cdi_name: format!("nvidia.com/gpu={}", index),
+                minor: Some(index),
+            })
+            .collect()
+    }
+
+    fn indices(picked: Option<Vec<GpuDevice>>) -> Option<Vec<u32>> {
+        picked.map(|set| set.iter().map(|d| d.index).collect())
+    }
+
+    #[test]
+    fn best_connected_set_wins() {
+        let topology = FakeTopology(HashMap::from([
+            ((0, 1), Link::HostBridge),
+            ((1, 2), Link::Switch),
+            ((2, 3), Link::NvLink),
+        ]));
+        let picked = pick_devices(&topology, &devices(4), 2, false).unwrap();
+        assert_eq!(indices(picked), Some(vec![2, 3]));
+        // Sets are judged by their worst link first
+        let picked = pick_devices(&topology, &devices(4), 3, false).unwrap();
+        assert_eq!(indices(picked), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn ties_go_to_the_lowest_indices() {
+        let topology = FakeTopology(HashMap::new());
+        let picked = pick_devices(&topology, &devices(4), 2, false).unwrap();
+        assert_eq!(indices(picked), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn p2p_can_be_required() {
+        let topology = FakeTopology(HashMap::from([
+            ((0, 1), Link::Node),
+            ((2, 3), Link::HostBridge),
+        ]));
+        let picked = pick_devices(&topology, &devices(4), 2, true).unwrap();
+        assert_eq!(indices(picked), Some(vec![2, 3]));
+        // Nothing else can do it
+        let picked = pick_devices(&topology, &devices(2), 2, true).unwrap();
+        assert_eq!(indices(picked), None);
+        // Unless it isn't required
+        let picked = pick_devices(&topology, &devices(2), 2, false).unwrap();
+        assert_eq!(indices(picked), Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn too_few_free_devices() {
+        let topology = FakeTopology(HashMap::new());
+        let picked = pick_devices(&topology, &devices(1), 2, false).unwrap();
+        assert_eq!(indices(picked), None);
+    }
+}
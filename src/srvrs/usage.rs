@@ -0,0 +1,189 @@
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    os::unix::process::ExitStatusExt,
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use crate::gpu::{self, GpuDevice};
+use crate::job::Job;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// What a job cost us. GPU memory is sampled from the devices the job was
+// given, so it is the peak of everything resident on those cards while the
+// script ran, and GPU time is the whole time it held them. Jobs sharing a
+// card are each charged for all of it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Usage {
+    pub wall_secs: f64, // How long the script ran
+    pub gpu_secs: BTreeMap<String, f64>, // Time each device was held, keyed by device UUID
+    pub peak_gpu_memory: u64, // Bytes
+    pub cpu_secs: f64, // User + system time of the script and everything it waited on
+}
+
+impl Usage {
+    pub fn total_gpu_secs(&self) -> f64 {
+        self.gpu_secs.values().sum()
+    }
+}
+
+// Reap the script ourselves instead of using `Child::wait`, so that we get its
+// rusage. The kernel folds the usage of every descendant the script waited on
+// into it, which covers the whole process tree of a well-behaved script.
+// Returns how it exited and the CPU time it used.
+pub fn wait_for_exit(child: &Child) -> Result<(ExitStatus, f64)> {
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, all zeroes is a valid value.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: status and rusage are valid for writes for the whole call.
+        let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+        if ret >= 0 {
+            break;
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e.into());
+        }
+    }
+    let secs = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    Ok((
+        ExitStatus::from_raw(status),
+        secs(rusage.ru_utime) + secs(rusage.ru_stime),
+    ))
+}
+
+// Polls the memory in use on a set of devices in the background and remembers
+// the highest value it saw.
+pub struct GpuMemorySampler {
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<u64>,
+}
+
+impl GpuMemorySampler {
+    pub fn start(devices: Vec<GpuDevice>) -> GpuMemorySampler {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut peak = 0;
+            let mut warned = false;
+            while !stopped.load(Ordering::Relaxed) {
+                match gpu::memory_used(&devices) {
+                    Ok(used) => peak = peak.max(used),
+                    Err(e) if !warned => {
+                        warn!("Could not sample GPU memory: {}", e);
+                        warned = true;
+                    }
+                    Err(_) => {}
+                }
+                thread::park_timeout(SAMPLE_INTERVAL);
+            }
+            peak
+        });
+        GpuMemorySampler { stop, handle }
+    }
+
+    // Stop sampling and return the peak, in bytes.
+    pub fn finish(self) -> u64 {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.thread().unpark();
+        self.handle.join().unwrap_or(0)
+    }
+}
+
+// One row of `srvrs usage`
+#[derive(Debug, Default)]
+pub struct UsageSummary {
+    pub user: String,
+    pub activity: String,
+    pub jobs: usize,
+    pub wall_secs: f64,
+    pub gpu_secs: f64,
+    pub peak_gpu_memory: u64,
+    pub cpu_secs: f64,
+}
+
+// What `srvrs usage` says about cards being shared, if they can be
+pub const SHARED_GPU_NOTE: &str = "GPU hours and peak VRAM are for whole cards, so jobs that shared one are each charged for all of it.";
+
+// Add up every finished job per user and per activity.
+pub fn summarize(jobs: &[Job], user: Option<&str>, since: Option<i64>) -> Vec<UsageSummary> {
+    let mut rows: BTreeMap<(String, String), UsageSummary> = BTreeMap::new();
+    for job in jobs {
+        if job.finished.is_none()
+            || user.is_some_and(|u| u != job.owner)
+            || since.is_some_and(|s| job.started < s)
+        {
+            continue;
+        }
+        let row = rows
+            .entry((job.owner.clone(), job.activity.clone()))
+            .or_insert_with(|| UsageSummary {
+                user: job.owner.clone(),
+                activity: job.activity.clone(),
+                ..Default::default()
+            });
+        row.jobs += 1;
+        row.wall_secs += job.usage.wall_secs;
+        row.gpu_secs += job.usage.total_gpu_secs();
+        row.peak_gpu_memory = row.peak_gpu_memory.max(job.usage.peak_gpu_memory);
+        row.cpu_secs += job.usage.cpu_secs;
+    }
+    rows.into_values().collect()
+}
+
+fn hours(secs: f64) -> f64 {
+    secs / 3600.0
+}
+
+fn mib(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
+
+pub fn print_table(rows: &[UsageSummary], shared_gpus: bool) {
+    println!(
+        "{:<16} {:<20} {:>6} {:>10} {:>10} {:>10} {:>15}",
+        "USER", "ACTIVITY", "JOBS", "WALL (h)", "GPU (h)", "CPU (h)", "PEAK VRAM (MiB)"
+    );
+    for r in rows {
+        println!(
+            "{:<16} {:<20} {:>6} {:>10.2} {:>10.2} {:>10.2} {:>15}",
+            r.user,
+            r.activity,
+            r.jobs,
+            hours(r.wall_secs),
+            hours(r.gpu_secs),
+            hours(r.cpu_secs),
+            mib(r.peak_gpu_memory)
+        );
+    }
+    if shared_gpus {
+        println!("\n{}", SHARED_GPU_NOTE);
+    }
+}
+
+pub fn print_csv(rows: &[UsageSummary]) {
+    println!("user,activity,jobs,wall_hours,gpu_hours,cpu_hours,peak_gpu_memory_mib");
+    for r in rows {
+        println!(
+            "{},{},{},{:.4},{:.4},{:.4},{}",
+            r.user,
+            r.activity,
+            r.jobs,
+            hours(r.wall_secs),
+            hours(r.gpu_secs),
+            hours(r.cpu_secs),
+            mib(r.peak_gpu_memory)
+        );
+    }
+}